@@ -0,0 +1,321 @@
+use crate::{memory, time};
+use ::acpi::{
+    AcpiError, AcpiTables, Handle, Handler, PciAddress, PhysicalMapping, aml::AmlError,
+    sdt::Signature,
+};
+use conquer_once::spin::OnceCell;
+use core::ptr::{self, NonNull};
+use log::{info, warn};
+use x86_64::{VirtAddr, instructions::port::Port};
+
+pub mod tables;
+
+pub use tables::{Fadt, Hpet, InterruptOverride, IoApicEntry, Madt, Mcfg, PciConfigRegion};
+
+/// The ACPI tables found through the RSDP the bootloader passed on.
+static REGISTRY: OnceCell<Registry> = OnceCell::uninit();
+
+/// The ACPI tables together with the typed tables read from them once.
+struct Registry {
+    handler: KernelAcpiHandler,
+    tables: AcpiTables<KernelAcpiHandler>,
+    madt: Option<Madt>,
+    fadt: Option<Fadt>,
+    hpet: Option<Hpet>,
+    mcfg: Option<Mcfg>,
+}
+
+/// Lets the `acpi` crate access physical memory, I/O ports and the PCI
+/// configuration space.
+///
+/// ACPI tables lie in memory the bootloader maps at the physical memory
+/// offset, so mapping a region just means adding the offset.
+#[derive(Debug, Clone, Copy)]
+pub struct KernelAcpiHandler {
+    phys_offset: VirtAddr,
+}
+
+impl KernelAcpiHandler {
+    fn virt(&self, address: usize) -> VirtAddr {
+        self.phys_offset + address as u64
+    }
+
+    fn read<T>(&self, address: usize) -> T {
+        unsafe { ptr::read_volatile(self.virt(address).as_ptr()) }
+    }
+
+    fn write<T>(&self, address: usize, value: T) {
+        unsafe { ptr::write_volatile(self.virt(address).as_mut_ptr(), value) }
+    }
+
+    /// Selects a register of the PCI configuration space through the legacy
+    /// port 0xcf8 mechanism and returns the data port that accesses it.
+    fn pci_port<T>(address: PciAddress, offset: u16) -> Port<T> {
+        let address = (1 << 31)
+            | (u32::from(address.bus()) << 16)
+            | (u32::from(address.device()) << 11)
+            | (u32::from(address.function()) << 8)
+            | (u32::from(offset) & 0xfc);
+        unsafe { Port::new(0xcf8).write(address) };
+        Port::new(0xcfc + (offset & 3))
+    }
+}
+
+impl Handler for KernelAcpiHandler {
+    unsafe fn map_physical_region<T>(
+        &self,
+        physical_address: usize,
+        size: usize,
+    ) -> PhysicalMapping<Self, T> {
+        PhysicalMapping {
+            physical_start: physical_address,
+            virtual_start: NonNull::new(self.virt(physical_address).as_mut_ptr())
+                .expect("physical memory offset is null"),
+            region_length: size,
+            mapped_length: size,
+            handler: *self,
+        }
+    }
+
+    fn unmap_physical_region<T>(_region: &PhysicalMapping<Self, T>) {}
+
+    fn read_u8(&self, address: usize) -> u8 {
+        self.read(address)
+    }
+
+    fn read_u16(&self, address: usize) -> u16 {
+        self.read(address)
+    }
+
+    fn read_u32(&self, address: usize) -> u32 {
+        self.read(address)
+    }
+
+    fn read_u64(&self, address: usize) -> u64 {
+        self.read(address)
+    }
+
+    fn write_u8(&self, address: usize, value: u8) {
+        self.write(address, value)
+    }
+
+    fn write_u16(&self, address: usize, value: u16) {
+        self.write(address, value)
+    }
+
+    fn write_u32(&self, address: usize, value: u32) {
+        self.write(address, value)
+    }
+
+    fn write_u64(&self, address: usize, value: u64) {
+        self.write(address, value)
+    }
+
+    fn read_io_u8(&self, port: u16) -> u8 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u16(&self, port: u16) -> u16 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn read_io_u32(&self, port: u16) -> u32 {
+        unsafe { Port::new(port).read() }
+    }
+
+    fn write_io_u8(&self, port: u16, value: u8) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u16(&self, port: u16, value: u16) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn write_io_u32(&self, port: u16, value: u32) {
+        unsafe { Port::new(port).write(value) }
+    }
+
+    fn read_pci_u8(&self, address: PciAddress, offset: u16) -> u8 {
+        unsafe { Self::pci_port(address, offset).read() }
+    }
+
+    fn read_pci_u16(&self, address: PciAddress, offset: u16) -> u16 {
+        unsafe { Self::pci_port(address, offset).read() }
+    }
+
+    fn read_pci_u32(&self, address: PciAddress, offset: u16) -> u32 {
+        unsafe { Self::pci_port(address, offset).read() }
+    }
+
+    fn write_pci_u8(&self, address: PciAddress, offset: u16, value: u8) {
+        unsafe { Self::pci_port(address, offset).write(value) }
+    }
+
+    fn write_pci_u16(&self, address: PciAddress, offset: u16, value: u16) {
+        unsafe { Self::pci_port(address, offset).write(value) }
+    }
+
+    fn write_pci_u32(&self, address: PciAddress, offset: u16, value: u32) {
+        unsafe { Self::pci_port(address, offset).write(value) }
+    }
+
+    fn nanos_since_boot(&self) -> u64 {
+        time::now().as_nanos()
+    }
+
+    fn stall(&self, microseconds: u64) {
+        io_delay(microseconds);
+    }
+
+    fn sleep(&self, milliseconds: u64) {
+        self.stall(milliseconds * 1000);
+    }
+
+    // no AML is executed concurrently, so its mutexes never block
+    fn create_mutex(&self) -> Handle {
+        Handle(0)
+    }
+
+    fn acquire(&self, _mutex: Handle, _timeout: u16) -> Result<(), AmlError> {
+        Ok(())
+    }
+
+    fn release(&self, _mutex: Handle) {}
+}
+
+/// Finds the ACPI tables through the RSDP at the physical address
+/// `rsdp_addr`, as passed by the bootloader, and reads the MADT, FADT, HPET
+/// and MCFG from them.
+///
+/// Tables that are missing or invalid are left out, only an invalid RSDP or
+/// XSDT is an error.
+///
+/// Must be called after [`memory::init_global`] and once the heap is
+/// initialized. Panics if called more than once.
+pub fn init(rsdp_addr: u64) -> Result<(), AcpiError> {
+    let handler = KernelAcpiHandler {
+        phys_offset: memory::mapper().phys_offset(),
+    };
+    let tables = unsafe { AcpiTables::from_rsdp(handler, rsdp_addr as usize)? };
+    let registry = Registry {
+        handler,
+        madt: optional(Signature::MADT, Madt::read(&tables)),
+        fadt: optional(Signature::FADT, Fadt::read(&tables)),
+        hpet: optional(Signature::HPET, Hpet::read(&tables)),
+        mcfg: optional(Signature::MCFG, Mcfg::read(&tables)),
+        tables,
+    };
+    REGISTRY
+        .try_init_once(|| registry)
+        .expect("acpi::init should only be called once");
+    Ok(())
+}
+
+/// Turns a missing table into `None`, and an invalid one too after warning
+/// about it.
+fn optional<T>(signature: Signature, table: Result<T, AcpiError>) -> Option<T> {
+    match table {
+        Ok(table) => Some(table),
+        Err(AcpiError::TableNotFound(_)) => None,
+        Err(err) => {
+            warn!("ignoring the invalid {} table: {:?}", signature, err);
+            None
+        }
+    }
+}
+
+/// Returns the ACPI tables, or `None` if [`init`] was not called or failed.
+pub fn tables() -> Option<&'static AcpiTables<KernelAcpiHandler>> {
+    REGISTRY.get().map(|registry| &registry.tables)
+}
+
+/// Returns the handler the ACPI tables were read with, for accessing the
+/// registers they describe, or `None` if [`init`] was not called or failed.
+pub fn handler() -> Option<KernelAcpiHandler> {
+    REGISTRY.get().map(|registry| registry.handler)
+}
+
+/// Returns the MADT, or `None` if there is none or it describes no APIC.
+pub fn madt() -> Option<&'static Madt> {
+    REGISTRY.get()?.madt.as_ref()
+}
+
+pub fn fadt() -> Option<&'static Fadt> {
+    REGISTRY.get()?.fadt.as_ref()
+}
+
+pub fn hpet() -> Option<&'static Hpet> {
+    REGISTRY.get()?.hpet.as_ref()
+}
+
+pub fn mcfg() -> Option<&'static Mcfg> {
+    REGISTRY.get()?.mcfg.as_ref()
+}
+
+/// Logs every ACPI table with its address and size, followed by what the
+/// kernel read from the MADT, FADT, HPET and MCFG.
+pub fn log_summary() {
+    let Some(registry) = REGISTRY.get() else {
+        return;
+    };
+    info!("ACPI tables (revision {}):", registry.tables.rsdp_revision);
+    for (address, header) in registry.tables.table_headers() {
+        info!(
+            "  {} at {:#x} ({} bytes, OEM {})",
+            header.signature,
+            address,
+            { header.length },
+            header.oem_id().unwrap_or("?")
+        );
+    }
+
+    match &registry.madt {
+        Some(madt) => info!(
+            "MADT: local APIC at {:#x}, {} I/O APIC(s), {} interrupt override(s), {} processor(s)",
+            madt.local_apic_address,
+            madt.io_apics.len(),
+            madt.interrupt_overrides.len(),
+            madt.processors.len()
+        ),
+        None => info!("MADT: no APIC"),
+    }
+    if let Some(fadt) = &registry.fadt {
+        info!(
+            "FADT: SCI on IRQ {}, PM timer {}, reset register {}, century register {:#x}",
+            fadt.sci_interrupt,
+            match fadt.pm_timer {
+                Some(_) => "present",
+                None => "missing",
+            },
+            match fadt.reset_register {
+                Some(_) => "present",
+                None => "missing",
+            },
+            fadt.century_register
+        );
+    }
+    match &registry.hpet {
+        Some(hpet) => info!(
+            "HPET: at {:#x} with {} comparators",
+            hpet.base_address, hpet.comparators
+        ),
+        None => info!("HPET: missing"),
+    }
+    if let Some(mcfg) = &registry.mcfg {
+        for region in &mcfg.regions {
+            info!(
+                "MCFG: segment {} buses {}-{} at {:#x}",
+                region.segment, region.bus_start, region.bus_end, region.base_address
+            );
+        }
+    }
+}
+
+/// Waits for about `microseconds`, without relying on any timer.
+pub fn io_delay(microseconds: u64) {
+    // a write to the POST code port takes about a microsecond
+    let mut port = Port::<u8>::new(0x80);
+    for _ in 0..microseconds {
+        unsafe { port.write(0) };
+    }
+}
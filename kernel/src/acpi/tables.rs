@@ -0,0 +1,250 @@
+use super::KernelAcpiHandler;
+use ::acpi::{
+    AcpiError, AcpiTables, HpetInfo,
+    address::GenericAddress,
+    platform::{
+        InterruptModel, ProcessorState,
+        interrupt::{Polarity, TriggerMode},
+    },
+    sdt::{Signature, fadt, mcfg},
+};
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+/// An I/O APIC listed in the MADT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IoApicEntry {
+    pub id: u8,
+    pub address: PhysAddr,
+    /// The first global system interrupt (GSI) it handles.
+    pub gsi_base: u32,
+}
+
+/// An ISA IRQ that is not identity mapped to a GSI, edge triggered and
+/// active high, from the MADT's interrupt source overrides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptOverride {
+    pub isa_irq: u8,
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+/// The Multiple APIC Description Table, describing the interrupt
+/// controllers and the processors.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_address: PhysAddr,
+    pub io_apics: Vec<IoApicEntry>,
+    pub interrupt_overrides: Vec<InterruptOverride>,
+    /// Whether there are 8259 PICs, which must be masked when using the
+    /// APIC.
+    pub has_legacy_pics: bool,
+    /// The local APIC IDs of the processors that can be used, the boot
+    /// processor first.
+    pub processors: Vec<u32>,
+}
+
+impl Madt {
+    pub(super) fn read(tables: &AcpiTables<KernelAcpiHandler>) -> Result<Self, AcpiError> {
+        let (model, processors) = InterruptModel::new(tables)?;
+        let InterruptModel::Apic(apic) = model else {
+            return Err(AcpiError::TableNotFound(Signature::MADT));
+        };
+
+        let io_apics = apic
+            .io_apics
+            .iter()
+            .map(|io_apic| IoApicEntry {
+                id: io_apic.id,
+                address: PhysAddr::new(u64::from(io_apic.address)),
+                gsi_base: io_apic.global_system_interrupt_base,
+            })
+            .collect();
+        let interrupt_overrides = apic
+            .interrupt_source_overrides
+            .iter()
+            .map(|source| InterruptOverride {
+                isa_irq: source.isa_source,
+                gsi: source.global_system_interrupt,
+                active_low: source.polarity == Polarity::ActiveLow,
+                level_triggered: source.trigger_mode == TriggerMode::Level,
+            })
+            .collect();
+        let processors = processors
+            .map(|processors| {
+                let application_processors = processors
+                    .application_processors
+                    .iter()
+                    .filter(|processor| processor.state != ProcessorState::Disabled);
+                core::iter::once(&processors.boot_processor)
+                    .chain(application_processors)
+                    .map(|processor| processor.local_apic_id)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Ok(Madt {
+            local_apic_address: PhysAddr::new(apic.local_apic_address),
+            io_apics,
+            interrupt_overrides,
+            has_legacy_pics: apic.also_has_legacy_pics,
+            processors,
+        })
+    }
+}
+
+/// The Fixed ACPI Description Table, describing the power management
+/// hardware.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    /// The ISA IRQ of the system control interrupt, which ACPI events are
+    /// signalled through.
+    pub sci_interrupt: u16,
+    /// The port `acpi_enable` and `acpi_disable` are written to to switch
+    /// between ACPI and legacy mode, or 0 if the system is always in ACPI
+    /// mode.
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_control: GenericAddress,
+    pub pm1b_control: Option<GenericAddress>,
+    /// The ACPI power management timer, a 3.579545 MHz counter.
+    pub pm_timer: Option<GenericAddress>,
+    /// Whether the power management timer has 32 instead of 24 bits.
+    pub pm_timer_32_bit: bool,
+    /// The register `reset_value` is written to to reset the system, if
+    /// it can be reset that way.
+    pub reset_register: Option<GenericAddress>,
+    pub reset_value: u8,
+    /// The index of the CMOS RTC's century register, or 0 if there is none.
+    pub century_register: u8,
+    /// Whether there is an 8042 keyboard controller. Assumed for FADTs older
+    /// than revision 2, which lack the flag.
+    pub has_8042: bool,
+    /// Whether the system has no fixed ACPI hardware, like PM1 registers.
+    pub hardware_reduced: bool,
+}
+
+impl Fadt {
+    pub(super) fn read(tables: &AcpiTables<KernelAcpiHandler>) -> Result<Self, AcpiError> {
+        let fadt = tables
+            .find_table::<fadt::Fadt>()
+            .ok_or(AcpiError::TableNotFound(Signature::FADT))?;
+
+        let flags = { fadt.flags };
+        let reset_register = match flags.supports_system_reset_via_fadt() {
+            true => Some(fadt.reset_register()?),
+            false => None,
+        };
+        let boot_arch = { fadt.iapc_boot_arch };
+        Ok(Fadt {
+            sci_interrupt: fadt.sci_interrupt,
+            smi_command_port: fadt.smi_cmd_port,
+            acpi_enable: fadt.acpi_enable,
+            acpi_disable: fadt.acpi_disable,
+            pm1a_control: fadt.pm1a_control_block()?,
+            pm1b_control: fadt.pm1b_control_block()?,
+            pm_timer: fadt.pm_timer_block()?,
+            pm_timer_32_bit: flags.pm_timer_is_32_bit(),
+            reset_register,
+            reset_value: fadt.reset_value,
+            century_register: fadt.century,
+            has_8042: fadt.header.revision < 2 || boot_arch.motherboard_implements_8042(),
+            hardware_reduced: flags.system_is_hw_reduced_acpi(),
+        })
+    }
+}
+
+/// The High Precision Event Timer Table.
+///
+/// The tick period and the comparators' capabilities are read from the
+/// HPET's own registers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hpet {
+    pub base_address: PhysAddr,
+    pub comparators: u8,
+    pub main_counter_64_bit: bool,
+    /// Whether the HPET can replace the PIT and the RTC on IRQ0 and IRQ8.
+    pub legacy_replacement: bool,
+    /// The smallest period in ticks a periodic comparator can be set to
+    /// without losing interrupts.
+    pub minimum_tick: u16,
+}
+
+impl Hpet {
+    pub(super) fn read(tables: &AcpiTables<KernelAcpiHandler>) -> Result<Self, AcpiError> {
+        let hpet = HpetInfo::new(tables)?;
+        Ok(Hpet {
+            base_address: PhysAddr::new(hpet.base_address as u64),
+            comparators: hpet.num_comparators,
+            main_counter_64_bit: hpet.main_counter_is_64bits,
+            legacy_replacement: hpet.legacy_irq_capable,
+            minimum_tick: hpet.clock_tick_unit,
+        })
+    }
+}
+
+/// The memory mapped PCI Express configuration space of a range of buses,
+/// from the MCFG.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciConfigRegion {
+    pub base_address: PhysAddr,
+    pub segment: u16,
+    pub bus_start: u8,
+    pub bus_end: u8,
+}
+
+impl PciConfigRegion {
+    /// The physical address of the 4 KiB configuration space of a function,
+    /// or `None` if its bus is not in this region.
+    pub fn function_address(&self, bus: u8, device: u8, function: u8) -> Option<PhysAddr> {
+        if !(self.bus_start..=self.bus_end).contains(&bus) || device >= 32 || function >= 8 {
+            return None;
+        }
+        let offset = u64::from(bus - self.bus_start) << 20
+            | u64::from(device) << 15
+            | u64::from(function) << 12;
+        Some(self.base_address + offset)
+    }
+}
+
+/// The PCI Express memory mapped configuration table.
+#[derive(Debug, Clone)]
+pub struct Mcfg {
+    pub regions: Vec<PciConfigRegion>,
+}
+
+impl Mcfg {
+    pub(super) fn read(tables: &AcpiTables<KernelAcpiHandler>) -> Result<Self, AcpiError> {
+        let mcfg = tables
+            .find_table::<mcfg::Mcfg>()
+            .ok_or(AcpiError::TableNotFound(Signature::MCFG))?;
+        let regions = mcfg
+            .entries()
+            .iter()
+            .map(|entry| PciConfigRegion {
+                base_address: PhysAddr::new(entry.base_address),
+                segment: entry.pci_segment_group,
+                bus_start: entry.bus_number_start,
+                bus_end: entry.bus_number_end,
+            })
+            .collect();
+        Ok(Mcfg { regions })
+    }
+
+    /// The physical address of the configuration space of a function, or
+    /// `None` if no region covers its bus.
+    pub fn function_address(
+        &self,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+    ) -> Option<PhysAddr> {
+        self.regions
+            .iter()
+            .filter(|region| region.segment == segment)
+            .find_map(|region| region.function_address(bus, device, function))
+    }
+}
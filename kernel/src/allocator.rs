@@ -1,26 +1,59 @@
+use crate::memory::vmm::{self, VirtRange, VmError};
 use alloc::alloc::{GlobalAlloc, Layout};
 use bump::BumpAllocator;
 use core::ptr::null_mut;
+use core::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(feature = "heap-debug")]
+use debug::DebugAllocator;
+#[cfg(feature = "fixed-size-block")]
+use fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(not(feature = "fixed-size-block"))]
 use linked_list::LinkedListAllocator;
 use linked_list_allocator::LockedHeap;
+use oom::OomHandler;
 use x86_64::{
     VirtAddr,
-    structures::paging::{
-        FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB, mapper::MapToError,
-    },
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB},
 };
 
 pub mod bump;
+#[cfg(feature = "heap-debug")]
+pub mod debug;
+pub mod fixed_size_block;
 pub mod linked_list;
+pub mod oom;
+pub mod slab;
 
+pub use oom::{try_box, try_vec};
+
+/// The allocator backend selected by the cargo features.
+#[cfg(feature = "fixed-size-block")]
+type Backend = FixedSizeBlockAllocator;
+#[cfg(not(feature = "fixed-size-block"))]
+type Backend = LinkedListAllocator;
+
+#[cfg(not(feature = "heap-debug"))]
+#[global_allocator]
+static ALLOCATOR: OomHandler<Locked<Backend>> = OomHandler::new(Locked::new(Backend::new()));
+#[cfg(feature = "heap-debug")]
 #[global_allocator]
-static ALLOCATOR: Locked<LinkedListAllocator> = Locked::new(LinkedListAllocator::new());
+static ALLOCATOR: OomHandler<DebugAllocator<Locked<Backend>>> =
+    OomHandler::new(DebugAllocator::new(Locked::new(Backend::new())));
 // static ALLOCATOR: Locked<BumpAllocator> = Locked::new(BumpAllocator::new());
 // static ALLOCATOR: LockedHeap = LockedHeap::empty();
 // static ALLOCATOR: Dummy = Dummy;
 
-pub const HEAP_START: usize = 0x_4444_4444_0000;
+pub const HEAP_START: usize = 0xffff_c000_0000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// Default upper bound for the heap size, see [`set_heap_limit`].
+pub const HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+/// The minimum amount of memory the heap grows by at once.
+const HEAP_GROWTH_STEP: usize = 64 * 1024; // 64 KiB
+
+/// End of the currently mapped heap memory.
+static HEAP_END: AtomicUsize = AtomicUsize::new(HEAP_START);
+/// Maximum size the heap is allowed to grow to.
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(HEAP_MAX_SIZE);
 
 /// A wrapper around spin::Mutex to permit trait implementations.
 pub struct Locked<A> {
@@ -39,6 +72,102 @@ impl<A> Locked<A> {
     }
 }
 
+/// A snapshot of the heap's usage, see [`heap_stats`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    /// Number of bytes currently mapped for the heap.
+    pub heap_size: usize,
+    /// Number of bytes currently handed out, as requested by the layouts.
+    pub allocated_bytes: usize,
+    /// Number of bytes that are available for new allocations without growing the heap.
+    pub free_bytes: usize,
+    /// Size of the largest free block.
+    pub largest_free_block: usize,
+    /// Number of separate free blocks.
+    pub free_regions: usize,
+    /// Number of successful allocations since boot.
+    pub allocations: u64,
+    /// Number of deallocations since boot.
+    pub deallocations: u64,
+    /// The highest value `allocated_bytes` has reached.
+    pub high_water_mark: usize,
+}
+
+/// Allocation counters shared by all allocator backends.
+#[derive(Debug, Clone, Copy, Default)]
+struct AllocCounters {
+    allocations: u64,
+    deallocations: u64,
+    allocated_bytes: usize,
+    high_water_mark: usize,
+}
+
+impl AllocCounters {
+    const fn new() -> Self {
+        AllocCounters {
+            allocations: 0,
+            deallocations: 0,
+            allocated_bytes: 0,
+            high_water_mark: 0,
+        }
+    }
+
+    fn record_alloc(&mut self, size: usize) {
+        self.allocations += 1;
+        self.allocated_bytes += size;
+        self.high_water_mark = self.high_water_mark.max(self.allocated_bytes);
+    }
+
+    fn record_dealloc(&mut self, size: usize) {
+        self.deallocations += 1;
+        self.allocated_bytes -= size;
+    }
+
+    /// Combines the counters with the free list information of a backend.
+    fn stats(
+        &self,
+        free_bytes: usize,
+        largest_free_block: usize,
+        free_regions: usize,
+    ) -> HeapStats {
+        HeapStats {
+            heap_size: heap_size(),
+            allocated_bytes: self.allocated_bytes,
+            free_bytes,
+            largest_free_block,
+            free_regions,
+            allocations: self.allocations,
+            deallocations: self.deallocations,
+            high_water_mark: self.high_water_mark,
+        }
+    }
+}
+
+/// Returns usage statistics of the global allocator.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Runs `f` and panics if it leaves allocations behind.
+///
+/// Intended for tests: everything `f` allocates must be freed again before
+/// it returns.
+pub fn assert_no_net_allocations<R>(f: impl FnOnce() -> R) -> R {
+    let before = heap_stats();
+    let result = f();
+    let after = heap_stats();
+
+    let leaked_allocations = (after.allocations - before.allocations) as i64
+        - (after.deallocations - before.deallocations) as i64;
+    assert!(
+        leaked_allocations == 0 && after.allocated_bytes == before.allocated_bytes,
+        "{} allocations ({} bytes) were not freed",
+        leaked_allocations,
+        after.allocated_bytes as isize - before.allocated_bytes as isize,
+    );
+    result
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
@@ -51,25 +180,14 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
-pub fn init_heap(
-    mapper: &mut impl Mapper<Size4KiB>,
-    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
-) -> Result<(), MapToError<Size4KiB>> {
-    let page_range = {
-        let heap_start = VirtAddr::new(HEAP_START as u64);
-        let heap_end = heap_start + HEAP_SIZE - 1u64;
-        let heap_start_page = Page::containing_address(heap_start);
-        let heap_end_page = Page::containing_address(heap_end);
-        Page::range_inclusive(heap_start_page, heap_end_page)
-    };
-
-    for page in page_range {
-        let frame = frame_allocator
-            .allocate_frame()
-            .ok_or(MapToError::FrameAllocationFailed)?;
-        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
-    }
+/// Maps the initial `HEAP_SIZE` bytes of the heap and initializes the
+/// global allocator with them.
+///
+/// Uses the kernel's global mapper and frame allocator, so
+/// [`memory::init_global`](crate::memory::init_global) must have been called before.
+pub fn init_heap() -> Result<(), VmError> {
+    vmm::map_huge(heap_range(HEAP_START, HEAP_SIZE), heap_flags())?;
+    HEAP_END.store(HEAP_START + HEAP_SIZE, Ordering::SeqCst);
 
     unsafe {
         ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
@@ -78,6 +196,61 @@ pub fn init_heap(
     Ok(())
 }
 
+/// Sets the maximum size the heap may grow to.
+///
+/// Memory that is already mapped is never given back, so a limit below the
+/// current heap size only prevents further growth.
+pub fn set_heap_limit(limit: usize) {
+    HEAP_LIMIT.store(limit, Ordering::SeqCst);
+}
+
+/// Returns the number of bytes currently mapped for the heap.
+pub fn heap_size() -> usize {
+    HEAP_END.load(Ordering::SeqCst) - HEAP_START
+}
+
+/// Maps at least `min_size` more bytes directly behind the end of the heap.
+///
+/// Returns the start address and size of the newly mapped region. Returns
+/// `None` if nothing could be mapped, e.g. because the heap limit is reached,
+/// physical memory ran out or the mapper is locked by the code that is
+/// currently allocating.
+///
+/// This is called by the allocator backends while they are locked, so it
+/// must not allocate.
+fn grow_heap(min_size: usize) -> Option<(usize, usize)> {
+    let start = HEAP_END.load(Ordering::SeqCst);
+    let limit = HEAP_START + HEAP_LIMIT.load(Ordering::SeqCst);
+    let mut size = align_up(min_size.max(HEAP_GROWTH_STEP), Size4KiB::SIZE as usize);
+    // large growths are extended to a 2 MiB boundary, so that the heap can
+    // be mapped with huge pages from there on
+    let huge_end = align_up(start + size, Size2MiB::SIZE as usize);
+    if size >= Size2MiB::SIZE as usize && huge_end <= limit {
+        size = huge_end - start;
+    }
+    // the last bit below the limit is still used if it is enough
+    let size = size.min(limit.saturating_sub(start));
+    if size == 0 || size < min_size {
+        return None;
+    }
+
+    // the mapper may be held by whoever triggered this allocation; spinning
+    // on it would deadlock, so give up instead
+    vmm::try_map_huge(heap_range(start, size), heap_flags()).ok()?;
+
+    HEAP_END.store(start + size, Ordering::SeqCst);
+    Some((start, size))
+}
+
+fn heap_range(start: usize, size: usize) -> VirtRange {
+    let size = align_up(size, Size4KiB::SIZE as usize);
+    VirtRange::new(VirtAddr::new(start as u64), size as u64)
+}
+
+fn heap_flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
 /// Align the given address `addr` upwards to alignment `align`.
 fn align_up(addr: usize, align: usize) -> usize {
     let remainder = addr % align;
@@ -1,4 +1,4 @@
-use super::{Locked, align_up};
+use super::{AllocCounters, HeapStats, Locked, align_up};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
 
@@ -7,6 +7,7 @@ pub struct BumpAllocator {
     heap_end: usize,
     next: usize,
     allocations: usize,
+    counters: AllocCounters,
 }
 
 impl BumpAllocator {
@@ -17,6 +18,7 @@ impl BumpAllocator {
             heap_end: 0,
             next: 0,
             allocations: 0,
+            counters: AllocCounters::new(),
         }
     }
 
@@ -29,6 +31,14 @@ impl BumpAllocator {
         self.heap_end = heap_start + heap_size;
         self.next = heap_start;
     }
+
+    /// Returns usage statistics of this allocator.
+    ///
+    /// The unused memory behind `next` is the only free region.
+    pub fn stats(&self) -> HeapStats {
+        let free = self.heap_end - self.next;
+        self.counters.stats(free, free, usize::from(free > 0))
+    }
 }
 
 unsafe impl GlobalAlloc for Locked<BumpAllocator> {
@@ -41,18 +51,29 @@ unsafe impl GlobalAlloc for Locked<BumpAllocator> {
             None => return ptr::null_mut(),
         };
 
+        if alloc_end > bump.heap_end {
+            // out of memory -> try to grow the heap, which always extends it
+            // directly behind the current end
+            match super::grow_heap(alloc_end - bump.heap_end) {
+                Some((start, size)) if start == bump.heap_end => bump.heap_end += size,
+                _ => return ptr::null_mut(),
+            }
+        }
+
         if alloc_end > bump.heap_end {
             ptr::null_mut() // out of memory
         } else {
             bump.next = alloc_end;
             bump.allocations += 1;
+            bump.counters.record_alloc(layout.size());
             alloc_start as *mut u8
         }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
+    unsafe fn dealloc(&self, _ptr: *mut u8, layout: Layout) {
         let mut bump = self.lock(); // get a mutable reference
 
+        bump.counters.record_dealloc(layout.size());
         bump.allocations -= 1;
         if bump.allocations == 0 {
             bump.next = bump.heap_start;
@@ -0,0 +1,142 @@
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::{mem, ops::Deref, ptr};
+use log::error;
+
+/// Marks the header of a live allocation.
+const ALLOCATED: u64 = 0xa110_ca7e_d0d0_a110;
+/// Marks the header of an allocation that was freed.
+const FREED: u64 = 0xf7ee_d0d0_f7ee_d0d0;
+/// Guards the bytes directly in front of an allocation.
+const CANARY: u64 = 0xcafe_babe_dead_beef;
+/// Size of the red zone behind every allocation.
+const RED_ZONE_SIZE: usize = 16;
+/// Pattern the red zone behind an allocation is filled with.
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Pattern fresh allocations are filled with.
+const ALLOC_POISON: u8 = 0xcd;
+/// Pattern freed memory is filled with.
+const FREE_POISON: u8 = 0xdd;
+/// Space kept free in front of the header, so that the free list node the
+/// backend writes on dealloc doesn't overwrite the `FREED` marker.
+const BACKEND_NODE_SIZE: usize = 16;
+
+/// Bookkeeping stored directly in front of every allocation.
+#[repr(C)]
+struct Header {
+    state: u64,
+    size: usize,
+    align: usize,
+    canary: u64,
+}
+
+/// A wrapper around an allocator backend that detects heap corruption.
+///
+/// Every allocation is surrounded by a header with a canary and a trailing
+/// red zone, new memory is filled with `0xcd` and freed memory with `0xdd`.
+/// `dealloc` checks the header and red zones and detects double frees and
+/// layouts that don't match the allocation; all problems are logged with the
+/// offending address before panicking.
+///
+/// Enabled by the `heap-debug` cargo feature. Derefs to the wrapped backend.
+pub struct DebugAllocator<A> {
+    inner: A,
+}
+
+impl<A> DebugAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        DebugAllocator { inner }
+    }
+
+    /// Returns the layout requested from the backend and the offset of the
+    /// user pointer into it.
+    fn outer_layout(layout: Layout) -> Option<(Layout, usize)> {
+        let align = layout.align().max(mem::align_of::<Header>());
+        let offset = (BACKEND_NODE_SIZE + mem::size_of::<Header>()).next_multiple_of(align);
+        let size = offset
+            .checked_add(layout.size())?
+            .checked_add(RED_ZONE_SIZE)?;
+        Some((Layout::from_size_align(size, align).ok()?, offset))
+    }
+}
+
+impl<A> Deref for DebugAllocator<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for DebugAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some((outer, offset)) = Self::outer_layout(layout) else {
+            return ptr::null_mut();
+        };
+        let start = unsafe { self.inner.alloc(outer) };
+        if start.is_null() {
+            return start;
+        }
+
+        unsafe {
+            let user = start.add(offset);
+            user.cast::<Header>().sub(1).write(Header {
+                state: ALLOCATED,
+                size: layout.size(),
+                align: layout.align(),
+                canary: CANARY,
+            });
+            user.write_bytes(ALLOC_POISON, layout.size());
+            user.add(layout.size())
+                .write_bytes(RED_ZONE_BYTE, RED_ZONE_SIZE);
+            user
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let (outer, offset) = Self::outer_layout(layout).expect("invalid layout in dealloc");
+        let header = unsafe { &mut *ptr.cast::<Header>().sub(1) };
+
+        match header.state {
+            ALLOCATED => {}
+            FREED => corruption(ptr, "double free"),
+            _ => corruption(
+                ptr,
+                "dealloc of a pointer that was not allocated, or header overwritten",
+            ),
+        }
+        if header.size != layout.size() || header.align != layout.align() {
+            error!(
+                "allocated with size {} align {}, freed with size {} align {}",
+                header.size,
+                header.align,
+                layout.size(),
+                layout.align()
+            );
+            corruption(ptr, "mismatched layout in dealloc");
+        }
+        if header.canary != CANARY {
+            corruption(
+                ptr,
+                "buffer underflow, canary in front of the allocation overwritten",
+            );
+        }
+        let red_zone =
+            unsafe { core::slice::from_raw_parts(ptr.add(layout.size()), RED_ZONE_SIZE) };
+        if let Some(index) = red_zone.iter().position(|&b| b != RED_ZONE_BYTE) {
+            error!("red zone byte {} behind the allocation overwritten", index);
+            corruption(ptr, "buffer overflow");
+        }
+
+        header.state = FREED;
+        unsafe {
+            ptr.write_bytes(FREE_POISON, layout.size());
+            self.inner.dealloc(ptr.sub(offset), outer);
+        }
+    }
+}
+
+/// Reports heap corruption at `ptr` through the logger and panics.
+fn corruption(ptr: *mut u8, what: &str) -> ! {
+    error!("HEAP CORRUPTION at {:p}: {}", ptr, what);
+    panic!("heap corruption at {:p}: {}", ptr, what);
+}
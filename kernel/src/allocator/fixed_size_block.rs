@@ -0,0 +1,145 @@
+use super::linked_list::LinkedListAllocator;
+use super::{AllocCounters, HeapStats, Locked};
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::mem;
+
+/// The block sizes to use.
+///
+/// The sizes must each be power of 2 because they are also used as
+/// the block alignment (alignments must be always powers of 2).
+const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+struct ListNode {
+    next: Option<&'static mut ListNode>,
+}
+
+/// An allocator that serves small allocations from per-size-class free lists
+/// and falls back to a [`LinkedListAllocator`] for large layouts and for
+/// refilling empty size classes.
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback_allocator: LinkedListAllocator,
+    counters: AllocCounters,
+}
+
+impl FixedSizeBlockAllocator {
+    /// Creates an empty FixedSizeBlockAllocator.
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback_allocator: LinkedListAllocator::new(),
+            counters: AllocCounters::new(),
+        }
+    }
+
+    /// Initialize the allocator with the given heap bounds.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the given heap bounds are valid and that
+    /// the heap is unused. This method must be called only once.
+    pub unsafe fn init(&mut self, heap_start: usize, heap_size: usize) {
+        unsafe {
+            self.fallback_allocator.init(heap_start, heap_size);
+        }
+    }
+
+    /// Allocates memory for the given layout.
+    ///
+    /// Returns a null pointer if the heap is exhausted.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let ptr = match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // no block exists in list => allocate new block
+                    let block_size = BLOCK_SIZES[index];
+                    // only works if all block sizes are a power of 2
+                    let block_align = block_size;
+                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                    self.fallback_allocator.allocate(layout)
+                }
+            },
+            None => self.fallback_allocator.allocate(layout),
+        };
+        if !ptr.is_null() {
+            self.counters.record_alloc(layout.size());
+        }
+        ptr
+    }
+
+    /// Returns the memory at `ptr` to its size class or the fallback allocator.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`allocate`](Self::allocate) on this
+    /// allocator with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        self.counters.record_dealloc(layout.size());
+        match list_index(&layout) {
+            Some(index) => {
+                let new_node = ListNode {
+                    next: self.list_heads[index].take(),
+                };
+                // verify that block has size and alignment required for storing node
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let new_node_ptr = ptr as *mut ListNode;
+                unsafe {
+                    new_node_ptr.write(new_node);
+                    self.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+            }
+            None => unsafe { self.fallback_allocator.deallocate(ptr, layout) },
+        }
+    }
+
+    /// Returns usage statistics of this allocator.
+    ///
+    /// Blocks cached in the size-class lists count as free regions of their
+    /// own, they are not given back to the fallback allocator.
+    pub fn stats(&self) -> HeapStats {
+        let fallback = self.fallback_allocator.stats();
+        let (mut free_bytes, mut largest, mut regions) = (
+            fallback.free_bytes,
+            fallback.largest_free_block,
+            fallback.free_regions,
+        );
+        for (head, &block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            let mut current = head.as_deref();
+            while let Some(node) = current {
+                free_bytes += block_size;
+                largest = largest.max(block_size);
+                regions += 1;
+                current = node.next.as_deref();
+            }
+        }
+        self.counters.stats(free_bytes, largest, regions)
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Choose an appropriate block size for the given layout.
+///
+/// Returns an index into the `BLOCK_SIZES` array.
+fn list_index(layout: &Layout) -> Option<usize> {
+    let required_block_size = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&s| s >= required_block_size)
+}
+
+unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.lock().allocate(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.lock().deallocate(ptr, layout) }
+    }
+}
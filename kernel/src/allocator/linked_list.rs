@@ -1,4 +1,4 @@
-use super::Locked;
+use super::{AllocCounters, HeapStats, Locked};
 use crate::allocator::align_up;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::mem;
@@ -25,6 +25,7 @@ impl ListNode {
 
 pub struct LinkedListAllocator {
     head: ListNode,
+    counters: AllocCounters,
 }
 
 impl LinkedListAllocator {
@@ -32,6 +33,7 @@ impl LinkedListAllocator {
     pub const fn new() -> Self {
         Self {
             head: ListNode::new(0),
+            counters: AllocCounters::new(),
         }
     }
 
@@ -46,19 +48,50 @@ impl LinkedListAllocator {
         }
     }
 
-    /// Adds the given memory region to the front of the list.
+    /// Inserts the given memory region into the address-ordered free list and
+    /// merges it with the neighbouring regions if they are adjacent.
     unsafe fn add_free_region(&mut self, addr: usize, size: usize) {
         // ensure that the freed region is capable of holding ListNode
         assert_eq!(align_up(addr, mem::align_of::<ListNode>()), addr);
         assert!(size >= mem::size_of::<ListNode>());
 
-        // create a new list node and append it at the start of the list
-        let mut node = ListNode::new(size);
-        node.next = self.head.next.take();
-        let node_ptr = addr as *mut ListNode;
-        unsafe {
-            node_ptr.write(node);
-            self.head.next = Some(&mut *node_ptr)
+        // find the last region that starts before the freed one (or the head)
+        let mut current = &mut self.head;
+        while current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() < addr)
+        {
+            current = current.next.as_mut().unwrap();
+        }
+
+        // the head is a dummy node with size 0 and never merged
+        let merge_prev = current.size > 0 && current.end_addr() == addr;
+        let merge_next = current
+            .next
+            .as_ref()
+            .is_some_and(|next| next.start_addr() == addr + size);
+
+        if merge_prev {
+            current.size += size;
+            if merge_next {
+                let next = current.next.take().unwrap();
+                current.size += next.size;
+                current.next = next.next.take();
+            }
+        } else {
+            let mut node = ListNode::new(size);
+            node.next = current.next.take();
+            if merge_next {
+                let next = node.next.take().unwrap();
+                node.size += next.size;
+                node.next = next.next.take();
+            }
+            let node_ptr = addr as *mut ListNode;
+            unsafe {
+                node_ptr.write(node);
+                current.next = Some(&mut *node_ptr)
+            }
         }
     }
 
@@ -92,7 +125,13 @@ impl LinkedListAllocator {
     ///
     /// Returns the allocation start address on success.
     fn alloc_from_region(region: &ListNode, size: usize, align: usize) -> Result<usize, ()> {
-        let alloc_start = align_up(region.start_addr(), align);
+        let mut alloc_start = align_up(region.start_addr(), align);
+        let padding = alloc_start - region.start_addr();
+        if padding > 0 && padding < mem::size_of::<ListNode>() {
+            // the padding in front of the allocation goes back to the free list,
+            // so it must be large enough to hold a ListNode as well
+            alloc_start = align_up(region.start_addr() + mem::size_of::<ListNode>(), align);
+        }
         let alloc_end = alloc_start.checked_add(size).ok_or(())?;
 
         if alloc_end > region.end_addr() {
@@ -111,6 +150,76 @@ impl LinkedListAllocator {
         Ok(alloc_start)
     }
 
+    /// Allocates memory for the given layout, growing the heap if no free
+    /// region is large enough.
+    ///
+    /// Returns a null pointer if the heap is exhausted.
+    pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        // perform layout adjustments
+        let (size, align) = LinkedListAllocator::size_align(layout);
+
+        let found = self.find_region(size, align).or_else(|| {
+            // out of memory -> grow the heap by enough to fit the allocation
+            // at any alignment and try again
+            let (start, grown) = super::grow_heap(size + align)?;
+            unsafe { self.add_free_region(start, grown) };
+            self.find_region(size, align)
+        });
+
+        if let Some((region, alloc_start)) = found {
+            let alloc_end = alloc_start.checked_add(size).expect("overflow");
+            let (region_start, region_end) = (region.start_addr(), region.end_addr());
+            let excess_size = region_end - alloc_end;
+            if excess_size > 0 {
+                unsafe {
+                    self.add_free_region(alloc_end, excess_size);
+                }
+            }
+            if alloc_start > region_start {
+                unsafe {
+                    self.add_free_region(region_start, alloc_start - region_start);
+                }
+            }
+            self.counters.record_alloc(layout.size());
+            alloc_start as *mut u8
+        } else {
+            ptr::null_mut()
+        }
+    }
+
+    /// Returns the memory at `ptr` to the free list.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`allocate`](Self::allocate) on this
+    /// allocator with the same `layout`.
+    pub unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        // perform layout adjustments
+        let (size, _) = LinkedListAllocator::size_align(layout);
+
+        self.counters.record_dealloc(layout.size());
+        unsafe { self.add_free_region(ptr as usize, size) }
+    }
+
+    /// Returns usage statistics of this allocator.
+    pub fn stats(&self) -> HeapStats {
+        let (free_bytes, largest, regions) = self.free_list_summary();
+        self.counters.stats(free_bytes, largest, regions)
+    }
+
+    /// Walks the free list and returns the total free bytes, the size of the
+    /// largest region and the number of regions.
+    fn free_list_summary(&self) -> (usize, usize, usize) {
+        let mut summary = (0, 0, 0);
+        let mut current = self.head.next.as_deref();
+        while let Some(region) = current {
+            summary.0 += region.size;
+            summary.1 = summary.1.max(region.size);
+            summary.2 += 1;
+            current = region.next.as_deref();
+        }
+        summary
+    }
+
     /// Adjust the given layout so that the resulting allocated memory
     /// region is also capable of storing a `ListNode`.
     ///
@@ -127,28 +236,10 @@ impl LinkedListAllocator {
 
 unsafe impl GlobalAlloc for Locked<LinkedListAllocator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // perform layout adjustments
-        let (size, align) = LinkedListAllocator::size_align(layout);
-        let mut allocator = self.lock();
-
-        if let Some((region, alloc_start)) = allocator.find_region(size, align) {
-            let alloc_end = alloc_start.checked_add(size).expect("overflow");
-            let excess_size = region.end_addr() - alloc_end;
-            if excess_size > 0 {
-                unsafe {
-                    allocator.add_free_region(alloc_end, excess_size);
-                }
-            }
-            alloc_start as *mut u8
-        } else {
-            ptr::null_mut()
-        }
+        self.lock().allocate(layout)
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        // perform layout adjustments
-        let (size, _) = LinkedListAllocator::size_align(layout);
-
-        unsafe { self.lock().add_free_region(ptr as usize, size) }
+        unsafe { self.lock().deallocate(ptr, layout) }
     }
 }
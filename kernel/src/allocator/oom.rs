@@ -0,0 +1,196 @@
+use super::{HEAP_LIMIT, heap_stats};
+use crate::memory::report::{self, Bytes};
+use alloc::alloc::{GlobalAlloc, Layout};
+use alloc::{boxed::Box, vec::Vec};
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use log::{error, info};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Maximum number of reclaim callbacks that can be registered at once.
+const MAX_RECLAIMERS: usize = 8;
+
+/// A callback that frees cached memory when the heap runs out.
+///
+/// It is passed the size of the failed allocation and returns the number of
+/// bytes it gave back to the heap.
+pub type ReclaimFn = fn(needed: usize) -> usize;
+
+/// The registered reclaim callbacks with their names. A fixed array, since
+/// the heap is full when they are needed.
+static RECLAIMERS: Mutex<[Option<(&'static str, ReclaimFn)>; MAX_RECLAIMERS]> =
+    Mutex::new([None; MAX_RECLAIMERS]);
+
+/// Number of [`fallible`] calls that are currently running, indexed by
+/// whether interrupts were enabled when they started. Interrupt handlers run
+/// with interrupts disabled, so they never see the sections they interrupted.
+static FALLIBLE_DEPTH: [AtomicUsize; 2] = [const { AtomicUsize::new(0) }; 2];
+
+/// The error returned by the fallible allocation helpers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocError;
+
+/// A wrapper around the global allocator that handles allocation failures.
+///
+/// When the wrapped allocator (which already tries to grow the heap) fails,
+/// the registered reclaim callbacks are run and the allocation is retried.
+/// If that doesn't help either, the heap and frame statistics are logged
+/// together with the failed layout and the kernel panics, unless the
+/// allocation was made inside [`fallible`], which gets a null pointer
+/// instead. Derefs to the wrapped allocator.
+pub struct OomHandler<A> {
+    inner: A,
+}
+
+impl<A> OomHandler<A> {
+    pub const fn new(inner: A) -> Self {
+        OomHandler { inner }
+    }
+}
+
+impl<A> Deref for OomHandler<A> {
+    type Target = A;
+
+    fn deref(&self) -> &A {
+        &self.inner
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for OomHandler<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.inner.alloc(layout) };
+        if !ptr.is_null() {
+            return ptr;
+        }
+        unsafe { self.out_of_memory(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.inner.dealloc(ptr, layout) }
+    }
+}
+
+impl<A: GlobalAlloc> OomHandler<A> {
+    #[cold]
+    unsafe fn out_of_memory(&self, layout: Layout) -> *mut u8 {
+        // the inner allocator is unlocked again, so the callbacks can free
+        if reclaim(layout.size()) > 0 {
+            let ptr = unsafe { self.inner.alloc(layout) };
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+        let depth = &FALLIBLE_DEPTH[interrupts::are_enabled() as usize];
+        if depth.load(Ordering::SeqCst) > 0 {
+            return ptr::null_mut();
+        }
+
+        let stats = heap_stats();
+        error!(
+            "out of memory: allocation of {} bytes (align {}) failed",
+            layout.size(),
+            layout.align()
+        );
+        error!(
+            "heap: {} mapped (limit {}), {} allocated, {} free, largest free block {}",
+            Bytes(stats.heap_size as u64),
+            Bytes(HEAP_LIMIT.load(Ordering::SeqCst) as u64),
+            Bytes(stats.allocated_bytes as u64),
+            Bytes(stats.free_bytes as u64),
+            Bytes(stats.largest_free_block as u64)
+        );
+        report::log_frame_usage();
+        panic!(
+            "out of memory: allocation of {} bytes failed, nothing left to reclaim",
+            layout.size()
+        );
+    }
+}
+
+/// Registers a callback that is asked to free memory when the heap runs out.
+///
+/// The callback must not register or unregister callbacks itself.
+///
+/// Panics if [`MAX_RECLAIMERS`] callbacks are registered already.
+pub fn register_reclaimer(name: &'static str, reclaim: ReclaimFn) {
+    let mut reclaimers = RECLAIMERS.lock();
+    let slot = reclaimers
+        .iter_mut()
+        .find(|slot| slot.is_none())
+        .expect("too many reclaim callbacks registered");
+    *slot = Some((name, reclaim));
+}
+
+/// Removes the callback registered under `name`.
+pub fn unregister_reclaimer(name: &'static str) {
+    for slot in RECLAIMERS.lock().iter_mut() {
+        if slot.is_some_and(|(slot_name, _)| slot_name == name) {
+            *slot = None;
+        }
+    }
+}
+
+/// Runs all reclaim callbacks and returns the number of bytes they freed.
+///
+/// The registry stays locked while they run, so a callback that runs out of
+/// memory itself doesn't reclaim again.
+fn reclaim(needed: usize) -> usize {
+    let Some(reclaimers) = RECLAIMERS.try_lock() else {
+        return 0;
+    };
+    let mut freed = 0;
+    for &(name, reclaim) in reclaimers.iter().flatten() {
+        let bytes = reclaim(needed);
+        if bytes > 0 {
+            info!("reclaimed {} from {}", Bytes(bytes as u64), name);
+        }
+        freed += bytes;
+    }
+    freed
+}
+
+/// Runs `f` with allocation failures reported as null pointers instead of
+/// panicking.
+///
+/// Only meant for allocations that handle failure, like [`Vec::try_reserve`].
+/// Applies to the allocations made while `f` runs with interrupts in the
+/// same state as on entry, so interrupt handlers that run meanwhile still
+/// panic when they run out of memory.
+///
+/// Parts of `f` that disable interrupts, like a nested
+/// [`interrupts::without_interrupts`], can't be told apart from interrupt
+/// handlers and still panic as well. Call `fallible` inside such a section
+/// instead of around it.
+pub fn fallible<R>(f: impl FnOnce() -> R) -> R {
+    let depth = &FALLIBLE_DEPTH[interrupts::are_enabled() as usize];
+    depth.fetch_add(1, Ordering::SeqCst);
+    let result = f();
+    depth.fetch_sub(1, Ordering::SeqCst);
+    result
+}
+
+/// Moves `value` to the heap, or returns an error if there is not enough
+/// memory left.
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        return Ok(Box::new(value));
+    }
+    let ptr = fallible(|| unsafe { alloc::alloc::alloc(layout) }).cast::<T>();
+    let ptr = NonNull::new(ptr).ok_or(AllocError)?;
+    unsafe {
+        ptr.write(value);
+        Ok(Box::from_raw(ptr.as_ptr()))
+    }
+}
+
+/// Creates a vector of `len` clones of `value` like `vec![value; len]`, or
+/// returns an error if there is not enough memory left.
+pub fn try_vec<T: Clone>(value: T, len: usize) -> Result<Vec<T>, AllocError> {
+    let mut vec = Vec::new();
+    fallible(|| vec.try_reserve_exact(len)).map_err(|_| AllocError)?;
+    vec.resize(len, value);
+    Ok(vec)
+}
@@ -0,0 +1,182 @@
+use super::Locked;
+use alloc::alloc::{Layout, alloc};
+use core::{
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr::{self, NonNull},
+};
+
+/// Size of the chunks a [`SlabCache`] requests from the heap at once.
+const SLAB_SIZE: usize = 4096;
+
+struct FreeSlot {
+    next: Option<NonNull<FreeSlot>>,
+}
+
+/// A cache of equally sized slots for objects of type `T`.
+///
+/// Slots are carved out of slabs of at least `SLAB_SIZE` bytes that are taken
+/// from the global allocator whenever the cache runs empty. Freed slots are
+/// kept in the cache for reuse by objects of the same type and are never
+/// given back to the heap.
+///
+/// Use it through a `Locked<SlabCache<T>>` static and [`Locked::alloc`].
+pub struct SlabCache<T> {
+    free_list: Option<NonNull<FreeSlot>>,
+    slabs: usize,
+    in_use: usize,
+    _marker: PhantomData<T>,
+}
+
+// the cache only hands out slots, the objects in them are owned by `SlabBox`
+unsafe impl<T: Send> Send for SlabCache<T> {}
+
+impl<T> SlabCache<T> {
+    /// Creates an empty SlabCache.
+    pub const fn new() -> Self {
+        SlabCache {
+            free_list: None,
+            slabs: 0,
+            in_use: 0,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the number of slabs requested from the heap so far.
+    pub fn slabs(&self) -> usize {
+        self.slabs
+    }
+
+    /// Returns the number of slots that currently hold an object.
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// Moves `value` into a free slot and returns a pointer to it.
+    ///
+    /// Returns `value` back if no slot is free and the heap is exhausted.
+    pub fn allocate(&mut self, value: T) -> Result<NonNull<T>, T> {
+        if self.free_list.is_none() && !self.grow() {
+            return Err(value);
+        }
+
+        let slot = self.free_list.take().unwrap();
+        unsafe {
+            self.free_list = slot.as_ref().next;
+            let object = slot.cast::<T>();
+            object.as_ptr().write(value);
+            self.in_use += 1;
+            Ok(object)
+        }
+    }
+
+    /// Drops the object at `object` and puts its slot back into the cache.
+    ///
+    /// # Safety
+    /// `object` must have been returned by [`allocate`](Self::allocate) on this
+    /// cache and must not be used afterwards.
+    pub unsafe fn deallocate(&mut self, object: NonNull<T>) {
+        unsafe {
+            ptr::drop_in_place(object.as_ptr());
+            self.release(object);
+        }
+    }
+
+    /// Puts the slot of an already dropped object back on the free list.
+    unsafe fn release(&mut self, object: NonNull<T>) {
+        let slot = object.cast::<FreeSlot>();
+        unsafe {
+            slot.as_ptr().write(FreeSlot {
+                next: self.free_list,
+            });
+        }
+        self.free_list = Some(slot);
+        self.in_use -= 1;
+    }
+
+    /// Adds a new slab to the free list.
+    fn grow(&mut self) -> bool {
+        let slot = Self::slot_layout();
+        let slab = Layout::from_size_align(SLAB_SIZE.max(slot.size()), slot.align()).unwrap();
+        let Some(start) = NonNull::new(unsafe { alloc(slab) }) else {
+            return false;
+        };
+
+        for index in (0..slab.size() / slot.size()).rev() {
+            unsafe {
+                let free = start.add(index * slot.size()).cast::<FreeSlot>();
+                free.as_ptr().write(FreeSlot {
+                    next: self.free_list,
+                });
+                self.free_list = Some(free);
+            }
+        }
+        self.slabs += 1;
+        true
+    }
+
+    /// The layout of a slot, which must be able to hold either a `T` or a
+    /// `FreeSlot`.
+    fn slot_layout() -> Layout {
+        let size = mem::size_of::<T>().max(mem::size_of::<FreeSlot>());
+        let align = mem::align_of::<T>().max(mem::align_of::<FreeSlot>());
+        Layout::from_size_align(size, align).unwrap().pad_to_align()
+    }
+}
+
+impl<T> Default for SlabCache<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Locked<SlabCache<T>> {
+    /// Moves `value` into this cache, returning an owning pointer that puts
+    /// the slot back when dropped.
+    ///
+    /// Panics if the heap is exhausted.
+    pub fn alloc(&'static self, value: T) -> SlabBox<T> {
+        let object = self
+            .lock()
+            .allocate(value)
+            .unwrap_or_else(|_| panic!("slab cache out of memory"));
+        SlabBox {
+            object,
+            cache: self,
+        }
+    }
+}
+
+/// An owning pointer to an object that lives in a [`SlabCache`].
+pub struct SlabBox<T: 'static> {
+    object: NonNull<T>,
+    cache: &'static Locked<SlabCache<T>>,
+}
+
+unsafe impl<T: Send> Send for SlabBox<T> {}
+unsafe impl<T: Sync> Sync for SlabBox<T> {}
+
+impl<T> Deref for SlabBox<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.object.as_ref() }
+    }
+}
+
+impl<T> DerefMut for SlabBox<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.object.as_mut() }
+    }
+}
+
+impl<T> Drop for SlabBox<T> {
+    fn drop(&mut self) {
+        // drop the object before locking, its destructor may use the cache too
+        unsafe {
+            ptr::drop_in_place(self.object.as_ptr());
+            self.cache.lock().release(self.object);
+        }
+    }
+}
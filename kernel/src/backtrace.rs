@@ -0,0 +1,210 @@
+//! Stack backtraces, walked along the frame pointers and symbolized with
+//! the symbol table of the kernel ELF file the bootloader loaded.
+
+use crate::elf::{self, ElfFile};
+use crate::memory;
+use bootloader_api::BootInfo;
+use conquer_once::spin::OnceCell;
+use core::arch::asm;
+use core::fmt;
+use log::{info, warn};
+use rustc_demangle::demangle;
+use x86_64::VirtAddr;
+use x86_64::structures::paging::Translate;
+
+/// Frames walked at most, in case the frame pointers are garbage.
+const MAX_FRAMES: usize = 64;
+
+/// The kernel ELF file, set by [`init`].
+static KERNEL: OnceCell<Kernel> = OnceCell::uninit();
+
+struct Kernel {
+    elf: ElfFile<'static>,
+    /// Where the bootloader loaded the kernel, symbol values are relative to
+    /// it.
+    image_offset: u64,
+}
+
+/// Makes the symbols of the kernel available for [`symbolize`].
+///
+/// Must be called after [`memory::init_global`]. Panics if called more than
+/// once.
+pub fn init(boot_info: &BootInfo) {
+    let phys_offset = memory::mapper().phys_offset();
+    let image = unsafe {
+        let start = phys_offset + boot_info.kernel_addr;
+        core::slice::from_raw_parts(start.as_ptr::<u8>(), boot_info.kernel_len as usize)
+    };
+    let elf = match ElfFile::parse(image) {
+        Ok(elf) => elf,
+        Err(err) => {
+            warn!(
+                "backtraces without symbols, can't parse the kernel: {:?}",
+                err
+            );
+            return;
+        }
+    };
+    let functions = elf
+        .symbols()
+        .filter(|symbol| symbol.kind == elf::STT_FUNC)
+        .count();
+    match functions {
+        0 => warn!("backtraces without symbols, the kernel is stripped"),
+        _ => info!("backtraces: {} function symbols", functions),
+    }
+    KERNEL
+        .try_init_once(|| Kernel {
+            elf,
+            image_offset: boot_info.kernel_image_offset,
+        })
+        .expect("backtrace::init should only be called once");
+}
+
+/// The function an address is in.
+#[derive(Debug, Clone, Copy)]
+pub struct Location {
+    /// The mangled name of the function.
+    pub name: &'static str,
+    /// The offset of the address from the start of the function.
+    pub offset: u64,
+}
+
+impl fmt::Display for Location {
+    /// Formats the demangled name without its hash, like
+    /// `kernel::task::executor::Executor::run+0x2c`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#}+{:#x}", demangle(self.name), self.offset)
+    }
+}
+
+/// Finds the function `address` is in, or `None` before [`init`] or for
+/// addresses outside of the kernel.
+pub fn symbolize(address: u64) -> Option<Location> {
+    let kernel = KERNEL.get()?;
+    let address = address.checked_sub(kernel.image_offset)?;
+    // functions written in assembly may have no size, they are assumed to
+    // reach up to the next symbol
+    kernel
+        .elf
+        .symbols()
+        .filter(|symbol| symbol.kind == elf::STT_FUNC && symbol.value <= address)
+        .filter(|symbol| symbol.size == 0 || address - symbol.value < symbol.size)
+        .max_by_key(|symbol| symbol.value)
+        .map(|symbol| Location {
+            name: symbol.name,
+            offset: address - symbol.value,
+        })
+}
+
+/// The return addresses on the stack, innermost first, see [`frames`].
+///
+/// Stops at a null frame pointer, which the kernel stacks start with, and
+/// at frame pointers that are unmapped or don't lead up the stack, which
+/// code compiled without frame pointers may leave. One frame pointer that
+/// leads down is followed, since an exception handler running on its own
+/// stack links back to the interrupted code on another one.
+#[derive(Debug, Clone)]
+pub struct Frames {
+    frame_pointer: u64,
+    depth: usize,
+    stack_switched: bool,
+}
+
+impl Iterator for Frames {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let frame_pointer = self.frame_pointer;
+        if self.depth >= MAX_FRAMES || !is_readable(frame_pointer) {
+            return None;
+        }
+        // the frame starts with the caller's frame pointer and the return
+        // address
+        let (caller_frame_pointer, return_address) = unsafe {
+            let frame = frame_pointer as *const u64;
+            (frame.read(), frame.add(1).read())
+        };
+        if return_address == 0 {
+            return None;
+        }
+        self.depth += 1;
+        self.frame_pointer = match caller_frame_pointer > frame_pointer {
+            true => caller_frame_pointer,
+            false if !self.stack_switched && caller_frame_pointer != frame_pointer => {
+                self.stack_switched = true;
+                caller_frame_pointer
+            }
+            false => 0,
+        };
+        Some(return_address)
+    }
+}
+
+/// Returns whether the frame at `frame_pointer` can be read without
+/// faulting. Frames aren't walked while the page tables are locked.
+fn is_readable(frame_pointer: u64) -> bool {
+    if frame_pointer == 0 || frame_pointer % 8 != 0 {
+        return false;
+    }
+    let Some(mapper) = memory::try_mapper() else {
+        return false;
+    };
+    [frame_pointer, frame_pointer + 8]
+        .into_iter()
+        .all(|address| {
+            VirtAddr::try_new(address).is_ok_and(|address| mapper.translate_addr(address).is_some())
+        })
+}
+
+/// Walks the stack from the frame at `frame_pointer`.
+pub fn frames_from(frame_pointer: u64) -> Frames {
+    Frames {
+        frame_pointer,
+        depth: 0,
+        stack_switched: false,
+    }
+}
+
+/// Walks the stack of the caller.
+#[inline(always)]
+pub fn frames() -> Frames {
+    let frame_pointer: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) frame_pointer, options(nomem, nostack, preserves_flags))
+    };
+    frames_from(frame_pointer)
+}
+
+/// A backtrace that is walked and symbolized while it is formatted, so that
+/// taking one doesn't allocate.
+#[derive(Debug, Clone)]
+pub struct Backtrace {
+    frames: Frames,
+}
+
+impl Backtrace {
+    /// The backtrace of the caller.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        Backtrace { frames: frames() }
+    }
+}
+
+impl fmt::Display for Backtrace {
+    /// Formats one frame per line, like
+    /// `  3: 0xffff80000012a3b4 kernel::task::executor::Executor::run+0x2c`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "backtrace:")?;
+        for (index, address) in self.frames.clone().enumerate() {
+            write!(f, "\n{:>3}: {:#018x}", index, address)?;
+            // the return address is after the call, which may be the last
+            // instruction of the function
+            if let Some(location) = symbolize(address - 1) {
+                let offset = location.offset + 1;
+                write!(f, " {}", Location { offset, ..location })?;
+            }
+        }
+        Ok(())
+    }
+}
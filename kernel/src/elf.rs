@@ -0,0 +1,229 @@
+//! A minimal reader for 64-bit little-endian ELF files.
+
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const SECTION_HEADER_SIZE: usize = 64;
+const SYMBOL_SIZE: usize = 24;
+
+/// Program header type of a loadable segment.
+pub const PT_LOAD: u32 = 1;
+/// Program header type of the range that is read-only after relocation.
+pub const PT_GNU_RELRO: u32 = 0x6474_e552;
+
+/// Segment flag: executable.
+pub const PF_X: u32 = 1;
+/// Segment flag: writable.
+pub const PF_W: u32 = 2;
+/// Segment flag: readable.
+pub const PF_R: u32 = 4;
+
+/// Section header type of a symbol table.
+pub const SHT_SYMTAB: u32 = 2;
+
+/// Symbol type of a function.
+pub const STT_FUNC: u8 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The data doesn't start with the ELF magic.
+    NotElf,
+    /// Not a 64-bit little-endian file.
+    Unsupported,
+    /// A header points outside of the data.
+    Truncated,
+}
+
+/// An ELF file in memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+}
+
+/// An entry of the program header table.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+}
+
+/// An entry of the section header table.
+#[derive(Debug, Clone, Copy)]
+pub struct SectionHeader {
+    pub kind: u32,
+    pub offset: u64,
+    pub size: u64,
+    /// The index of a related section, the string table for symbol tables.
+    pub link: u32,
+    pub entry_size: u64,
+}
+
+/// An entry of the symbol table.
+#[derive(Debug, Clone, Copy)]
+pub struct Symbol<'a> {
+    /// The name as it is in the file, so usually mangled.
+    pub name: &'a str,
+    pub kind: u8,
+    pub value: u64,
+    pub size: u64,
+}
+
+impl<'a> ElfFile<'a> {
+    /// Checks the ELF header of `data`.
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if &data[..4] != ELF_MAGIC {
+            return Err(ElfError::NotElf);
+        }
+        if data[4] != CLASS_64 || data[5] != DATA_LITTLE_ENDIAN {
+            return Err(ElfError::Unsupported);
+        }
+
+        let elf = ElfFile { data };
+        let table_end = elf
+            .program_header_count()
+            .checked_mul(elf.program_header_entry_size())
+            .and_then(|size| size.checked_add(elf.program_header_offset()));
+        if elf.program_header_entry_size() < PROGRAM_HEADER_SIZE
+            || table_end.is_none_or(|end| end > data.len())
+        {
+            return Err(ElfError::Truncated);
+        }
+        let section_table_end = elf
+            .section_header_count()
+            .checked_mul(elf.section_header_entry_size())
+            .and_then(|size| size.checked_add(elf.section_header_offset()));
+        if elf.section_header_count() > 0
+            && (elf.section_header_entry_size() < SECTION_HEADER_SIZE
+                || section_table_end.is_none_or(|end| end > data.len()))
+        {
+            return Err(ElfError::Truncated);
+        }
+        Ok(elf)
+    }
+
+    /// The raw bytes of the file.
+    pub fn data(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// Iterates over the program headers.
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + 'a {
+        let data = self.data;
+        let offset = self.program_header_offset();
+        let entry_size = self.program_header_entry_size();
+        (0..self.program_header_count()).map(move |index| {
+            let header = &data[offset + index * entry_size..];
+            ProgramHeader {
+                kind: read_u32(header, 0),
+                flags: read_u32(header, 4),
+                offset: read_u64(header, 8),
+                vaddr: read_u64(header, 16),
+                file_size: read_u64(header, 32),
+                mem_size: read_u64(header, 40),
+            }
+        })
+    }
+
+    /// Iterates over the section headers.
+    pub fn section_headers(&self) -> impl Iterator<Item = SectionHeader> + 'a {
+        let data = self.data;
+        let offset = self.section_header_offset();
+        let entry_size = self.section_header_entry_size();
+        (0..self.section_header_count()).map(move |index| {
+            let header = &data[offset + index * entry_size..];
+            SectionHeader {
+                kind: read_u32(header, 4),
+                offset: read_u64(header, 24),
+                size: read_u64(header, 32),
+                link: read_u32(header, 40),
+                entry_size: read_u64(header, 56),
+            }
+        })
+    }
+
+    /// The contents of `section`, or `None` if it lies outside of the file.
+    pub fn section_data(&self, section: &SectionHeader) -> Option<&'a [u8]> {
+        let start = usize::try_from(section.offset).ok()?;
+        let end = start.checked_add(usize::try_from(section.size).ok()?)?;
+        self.data.get(start..end)
+    }
+
+    /// Iterates over the symbols of the symbol table, which is empty if the
+    /// file was stripped.
+    pub fn symbols(&self) -> impl Iterator<Item = Symbol<'a>> + 'a {
+        let table = self
+            .section_headers()
+            .find(|section| section.kind == SHT_SYMTAB);
+        let (symbols, names, entry_size) = table
+            .and_then(|table| {
+                let names = self.section_headers().nth(table.link as usize)?;
+                Some((
+                    self.section_data(&table)?,
+                    self.section_data(&names)?,
+                    (table.entry_size as usize).max(SYMBOL_SIZE),
+                ))
+            })
+            .unwrap_or((&[], &[], SYMBOL_SIZE));
+        symbols.chunks_exact(entry_size).map(move |symbol| Symbol {
+            name: read_str(names, read_u32(symbol, 0) as usize),
+            kind: symbol[4] & 0xf,
+            value: read_u64(symbol, 8),
+            size: read_u64(symbol, 16),
+        })
+    }
+
+    fn program_header_offset(&self) -> usize {
+        read_u64(self.data, 0x20) as usize
+    }
+
+    fn program_header_entry_size(&self) -> usize {
+        read_u16(self.data, 0x36) as usize
+    }
+
+    fn program_header_count(&self) -> usize {
+        read_u16(self.data, 0x38) as usize
+    }
+
+    fn section_header_offset(&self) -> usize {
+        read_u64(self.data, 0x28) as usize
+    }
+
+    fn section_header_entry_size(&self) -> usize {
+        read_u16(self.data, 0x3a) as usize
+    }
+
+    fn section_header_count(&self) -> usize {
+        read_u16(self.data, 0x3c) as usize
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// The NUL-terminated string at `offset` of a string table, or an empty
+/// string if there is none.
+fn read_str(strings: &[u8], offset: usize) -> &str {
+    strings
+        .get(offset..)
+        .and_then(|rest| core::ffi::CStr::from_bytes_until_nul(rest).ok())
+        .and_then(|name| name.to_str().ok())
+        .unwrap_or("")
+}
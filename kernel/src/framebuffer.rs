@@ -83,6 +83,21 @@ impl FrameBufferWriter {
         self.framebuffer.fill(0);
     }
 
+    /// The memory the writer draws into.
+    pub fn buffer(&self) -> &[u8] {
+        self.framebuffer
+    }
+
+    /// Makes the writer draw into `framebuffer` from now on.
+    ///
+    /// # Safety
+    /// `framebuffer` must be another mapping of the memory of the current
+    /// buffer.
+    pub unsafe fn set_buffer(&mut self, framebuffer: &'static mut [u8]) {
+        assert_eq!(framebuffer.len(), self.framebuffer.len());
+        self.framebuffer = framebuffer;
+    }
+
     fn width(&self) -> usize {
         self.info.width
     }
@@ -4,6 +4,12 @@ use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector
 use x86_64::structures::tss::TaskStateSegment;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The page fault handler gets its own stack so that it still works when a
+/// kernel stack overflows into its guard page.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+/// NMIs arrive at any point, also while the kernel stack is unusable, so
+/// they get their own stack too.
+pub const NMI_IST_INDEX: u16 = 2;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
@@ -16,6 +22,22 @@ lazy_static! {
 
             stack_start + STACK_SIZE
         };
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+
+            stack_start + STACK_SIZE
+        };
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(&raw const STACK);
+
+            stack_start + STACK_SIZE
+        };
         tss
     };
     static ref GDT: (GlobalDescriptorTable, Selectors, SegmentSelector) = {
@@ -0,0 +1,328 @@
+use super::{InterruptIndex, PICS, irq};
+use crate::acpi;
+use crate::memory::vmm::{self, VmError};
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::ptr;
+use log::{info, warn};
+use spin::Mutex;
+use x86_64::{VirtAddr, instructions::interrupts};
+
+/// Vector of the local APIC's spurious interrupts, which need no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+// local APIC registers, as offsets into its MMIO page
+const LAPIC_ID: usize = 0x20;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_SPURIOUS: usize = 0xf0;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_TIMER_INITIAL_COUNT: usize = 0x380;
+const LAPIC_TIMER_CURRENT_COUNT: usize = 0x390;
+const LAPIC_TIMER_DIVIDE: usize = 0x3e0;
+
+const LAPIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+/// Divide configuration for dividing the bus clock by 16.
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+/// Initial count of the local APIC timer until it is calibrated, which
+/// gives a tick every few milliseconds on usual bus clocks.
+const DEFAULT_TIMER_COUNT: u32 = 0x10_0000;
+
+// I/O APIC registers, selected through IOREGSEL and accessed through IOWIN
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// The local APIC of the boot processor, once [`init`] switched to it.
+static LOCAL_APIC: OnceCell<LocalApic> = OnceCell::uninit();
+/// The I/O APICs and how ISA IRQs are wired to them.
+static IO_APICS: OnceCell<Mutex<IoApics>> = OnceCell::uninit();
+
+/// An error returned when switching to the APIC.
+#[derive(Debug)]
+pub enum ApicError {
+    /// The ACPI tables weren't read or have no MADT describing an APIC.
+    NoApic,
+    Vm(VmError),
+}
+
+impl From<VmError> for ApicError {
+    fn from(err: VmError) -> Self {
+        ApicError::Vm(err)
+    }
+}
+
+/// The local APIC of a processor, accessed through its MMIO page.
+#[derive(Debug)]
+pub struct LocalApic {
+    base: VirtAddr,
+}
+
+impl LocalApic {
+    fn read(&self, register: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base + register as u64).as_ptr()) }
+    }
+
+    fn write(&self, register: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base + register as u64).as_mut_ptr(), value) }
+    }
+
+    /// The APIC ID of the processor.
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Signals the end of the interrupt that is being handled.
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    /// Starts the timer, which raises `vector` after `initial_count` ticks of
+    /// the bus clock divided by 16, and then again every `initial_count`
+    /// ticks if `periodic` is set.
+    pub fn start_timer(&self, vector: u8, initial_count: u32, periodic: bool) {
+        let mode = if periodic { LVT_TIMER_PERIODIC } else { 0 };
+        self.write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+        self.write(LAPIC_LVT_TIMER, mode | u32::from(vector));
+        self.write(LAPIC_TIMER_INITIAL_COUNT, initial_count);
+    }
+
+    /// Stops the timer and returns the ticks that were left.
+    pub fn stop_timer(&self) -> u32 {
+        let remaining = self.read(LAPIC_TIMER_CURRENT_COUNT);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_TIMER_INITIAL_COUNT, 0);
+        remaining
+    }
+
+    /// Masks or unmasks the timer interrupt, the timer keeps counting.
+    pub fn set_timer_masked(&self, masked: bool) {
+        let lvt = self.read(LAPIC_LVT_TIMER);
+        match masked {
+            true => self.write(LAPIC_LVT_TIMER, lvt | LVT_MASKED),
+            false => self.write(LAPIC_LVT_TIMER, lvt & !LVT_MASKED),
+        }
+    }
+
+    /// The ticks left until the timer fires.
+    pub fn timer_count(&self) -> u32 {
+        self.read(LAPIC_TIMER_CURRENT_COUNT)
+    }
+}
+
+/// An I/O APIC, accessed through its MMIO registers.
+#[derive(Debug)]
+struct IoApic {
+    base: VirtAddr,
+    /// The first global system interrupt (GSI) this I/O APIC handles.
+    gsi_base: u32,
+    /// The number of inputs, i.e. of redirection entries.
+    inputs: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.base.as_mut_ptr(), register);
+            ptr::read_volatile((self.base + 0x10u64).as_ptr())
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.base.as_mut_ptr(), register);
+            ptr::write_volatile((self.base + 0x10u64).as_mut_ptr(), value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        (self.gsi_base..self.gsi_base + self.inputs).contains(&gsi)
+    }
+
+    fn redirection(&self, gsi: u32) -> u64 {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        u64::from(self.read(register)) | u64::from(self.read(register + 1)) << 32
+    }
+
+    fn set_redirection(&self, gsi: u32, entry: u64) {
+        let register = IOAPIC_REDIRECTION_TABLE + 2 * (gsi - self.gsi_base);
+        // masked while the two halves don't match
+        self.write(register, REDIRECTION_MASKED as u32);
+        self.write(register + 1, (entry >> 32) as u32);
+        self.write(register, entry as u32);
+    }
+}
+
+/// How an ISA IRQ is wired to the I/O APICs, from the MADT's interrupt
+/// source overrides.
+#[derive(Debug, Clone, Copy)]
+struct IsaRoute {
+    gsi: u32,
+    active_low: bool,
+    level_triggered: bool,
+}
+
+#[derive(Debug)]
+struct IoApics {
+    io_apics: Vec<IoApic>,
+    isa_routes: [IsaRoute; 16],
+}
+
+impl IoApics {
+    fn io_apic(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))
+    }
+
+    /// The route of the ISA IRQ `irq` and the I/O APIC it ends at.
+    fn route(&self, irq: u8) -> Result<(IsaRoute, &IoApic), irq::IrqError> {
+        let route = *self
+            .isa_routes
+            .get(usize::from(irq))
+            .ok_or(irq::IrqError::InvalidIrq)?;
+        let io_apic = self.io_apic(route.gsi).ok_or(irq::IrqError::Unroutable)?;
+        Ok((route, io_apic))
+    }
+}
+
+/// Switches interrupt delivery from the 8259 PICs to the APIC described by
+/// the MADT.
+///
+/// Both PICs are masked, the IRQs that have handlers registered are routed
+/// through the I/O APIC to the local APIC of this processor and the local
+/// APIC timer takes over the timer IRQ from the PIT, see [`start_timer`].
+/// All other I/O APIC inputs stay masked until a handler is registered with
+/// [`irq::register_irq`].
+///
+/// Must be called after [`acpi::init`] and once the heap is initialized.
+pub fn init() -> Result<(), ApicError> {
+    let madt = acpi::madt().ok_or(ApicError::NoApic)?;
+
+    let local_apic = LocalApic {
+        base: vmm::map_mmio(madt.local_apic_address, 0x1000)?,
+    };
+    let mut io_apics = Vec::new();
+    for io_apic in &madt.io_apics {
+        let mut io_apic = IoApic {
+            base: vmm::map_mmio(io_apic.address, 0x20)?,
+            gsi_base: io_apic.gsi_base,
+            inputs: 0,
+        };
+        io_apic.inputs = (io_apic.read(IOAPIC_VERSION) >> 16 & 0xff) + 1;
+        io_apics.push(io_apic);
+    }
+
+    // ISA IRQs are identity mapped, edge triggered and active high unless
+    // overridden
+    let mut isa_routes = [IsaRoute {
+        gsi: 0,
+        active_low: false,
+        level_triggered: false,
+    }; 16];
+    for (irq, route) in isa_routes.iter_mut().enumerate() {
+        route.gsi = irq as u32;
+    }
+    for source in &madt.interrupt_overrides {
+        if let Some(route) = isa_routes.get_mut(usize::from(source.isa_irq)) {
+            *route = IsaRoute {
+                gsi: source.gsi,
+                active_low: source.active_low,
+                level_triggered: source.level_triggered,
+            };
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        unsafe { PICS.lock().disable() };
+        for io_apic in &io_apics {
+            for gsi in io_apic.gsi_base..io_apic.gsi_base + io_apic.inputs {
+                io_apic.set_redirection(gsi, REDIRECTION_MASKED);
+            }
+        }
+        local_apic.write(
+            LAPIC_SPURIOUS,
+            LAPIC_SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR),
+        );
+        info!(
+            "switched to the APIC: local APIC {} at {:#x}, {} I/O APIC(s)",
+            local_apic.id(),
+            madt.local_apic_address,
+            io_apics.len()
+        );
+
+        IO_APICS.init_once(|| {
+            Mutex::new(IoApics {
+                io_apics,
+                isa_routes,
+            })
+        });
+        LOCAL_APIC.init_once(|| local_apic);
+        // IRQ0 stays masked, the local APIC timer replaces the PIT
+        for isa_irq in 1..irq::IRQ_COUNT {
+            if irq::is_registered(isa_irq)
+                && let Err(err) = route_isa_irq(isa_irq, irq::vector(isa_irq))
+            {
+                warn!(
+                    "IRQ {} has a handler but can't be routed: {:?}",
+                    isa_irq, err
+                );
+            }
+        }
+        start_timer(DEFAULT_TIMER_COUNT);
+    });
+    Ok(())
+}
+
+/// Returns the local APIC, or `None` if interrupts still go through the PICs.
+pub fn local_apic() -> Option<&'static LocalApic> {
+    LOCAL_APIC.get()
+}
+
+/// Returns whether [`init`] switched to the APIC.
+pub fn is_enabled() -> bool {
+    LOCAL_APIC.is_initialized()
+}
+
+/// Starts the local APIC timer as the periodic timer tick on the timer
+/// vector, with `initial_count` bus clock ticks (divided by 16) between two
+/// interrupts.
+pub fn start_timer(initial_count: u32) {
+    if let Some(local_apic) = local_apic() {
+        local_apic.start_timer(InterruptIndex::Timer.as_u8(), initial_count, true);
+    }
+}
+
+/// Routes the ISA IRQ `irq` to `vector` on this processor and unmasks it.
+///
+/// Panics if the APIC is not enabled.
+pub fn route_isa_irq(irq: u8, vector: u8) -> Result<(), irq::IrqError> {
+    let io_apics = IO_APICS.get().expect("APIC not enabled").lock();
+    let (route, io_apic) = io_apics.route(irq)?;
+
+    let mut entry = u64::from(vector) | u64::from(local_apic().map_or(0, LocalApic::id)) << 56;
+    if route.active_low {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if route.level_triggered {
+        entry |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    io_apic.set_redirection(route.gsi, entry);
+    Ok(())
+}
+
+/// Masks or unmasks the ISA IRQ `irq` in the I/O APIC.
+///
+/// Panics if the APIC is not enabled.
+pub fn set_isa_irq_masked(irq: u8, masked: bool) -> Result<(), irq::IrqError> {
+    let io_apics = IO_APICS.get().expect("APIC not enabled").lock();
+    let (route, io_apic) = io_apics.route(irq)?;
+    let entry = io_apic.redirection(route.gsi);
+    match masked {
+        true => io_apic.set_redirection(route.gsi, entry | REDIRECTION_MASKED),
+        false => io_apic.set_redirection(route.gsi, entry & !REDIRECTION_MASKED),
+    }
+    Ok(())
+}
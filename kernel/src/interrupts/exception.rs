@@ -0,0 +1,425 @@
+use crate::backtrace;
+use crate::gdt;
+use crate::memory;
+use core::arch::naked_asm;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use log::trace;
+use x86_64::VirtAddr;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{
+    InterruptDescriptorTable, InterruptStackFrameValue, PageFaultErrorCode, SelectorErrorCode,
+};
+
+const DEBUG: u8 = 1;
+const NON_MASKABLE_INTERRUPT: u8 = 2;
+const BREAKPOINT: u8 = 3;
+const INVALID_TSS: u8 = 10;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT_FAULT: u8 = 12;
+const GENERAL_PROTECTION_FAULT: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+
+/// Non-maskable interrupts so far.
+static NMI_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// The names of the exceptions, by vector.
+const NAMES: [&str; 32] = [
+    "divide error",
+    "debug",
+    "non-maskable interrupt",
+    "breakpoint",
+    "overflow",
+    "bound range exceeded",
+    "invalid opcode",
+    "device not available",
+    "double fault",
+    "coprocessor segment overrun",
+    "invalid TSS",
+    "segment not present",
+    "stack segment fault",
+    "general protection fault",
+    "page fault",
+    "reserved",
+    "x87 floating point",
+    "alignment check",
+    "machine check",
+    "SIMD floating point",
+    "virtualization",
+    "control protection",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "reserved",
+    "hypervisor injection",
+    "VMM communication",
+    "security",
+    "reserved",
+];
+
+/// The general purpose registers of the interrupted code, in the order
+/// [`common_entry`] pushes them.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+/// Everything on the stack when an exception reaches [`handle_exception`].
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub registers: Registers,
+    pub vector: u64,
+    /// The error code the CPU pushed, or 0 for exceptions without one.
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl ExceptionFrame {
+    pub fn name(&self) -> &'static str {
+        NAMES
+            .get(self.vector as usize)
+            .copied()
+            .unwrap_or("unknown")
+    }
+}
+
+impl fmt::Display for ExceptionFrame {
+    /// Formats the exception, its decoded error code and all registers, one
+    /// group per line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = &self.stack_frame;
+        let registers = &self.registers;
+        write!(
+            f,
+            "{} (vector {}) at {:#x}",
+            self.name(),
+            self.vector,
+            frame.instruction_pointer.as_u64()
+        )?;
+        match backtrace::symbolize(frame.instruction_pointer.as_u64()) {
+            Some(location) => writeln!(f, " in {}", location)?,
+            None => writeln!(f)?,
+        }
+        match self.vector as u8 {
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT
+                if self.error_code != 0 =>
+            {
+                let code = SelectorErrorCode::new_truncate(self.error_code);
+                writeln!(
+                    f,
+                    "error code {:#x}: selector {} in the {:?}{}",
+                    self.error_code,
+                    code.index(),
+                    code.descriptor_table(),
+                    match code.external() {
+                        true => ", during an external event",
+                        false => "",
+                    }
+                )?;
+            }
+            PAGE_FAULT => writeln!(
+                f,
+                "error code {:#x}: {:?}",
+                self.error_code,
+                PageFaultErrorCode::from_bits_truncate(self.error_code)
+            )?,
+            vector if has_error_code(vector) => writeln!(f, "error code {:#x}", self.error_code)?,
+            _ => {}
+        }
+        writeln!(
+            f,
+            "rip {:#018x} cs  {:#06x} (ring {}) rflags {:#x}",
+            frame.instruction_pointer.as_u64(),
+            frame.code_segment,
+            frame.code_segment & 3,
+            frame.cpu_flags
+        )?;
+        writeln!(
+            f,
+            "rsp {:#018x} ss  {:#06x}",
+            frame.stack_pointer.as_u64(),
+            frame.stack_segment
+        )?;
+        let rows = [
+            [
+                ("rax", registers.rax),
+                ("rbx", registers.rbx),
+                ("rcx", registers.rcx),
+            ],
+            [
+                ("rdx", registers.rdx),
+                ("rsi", registers.rsi),
+                ("rdi", registers.rdi),
+            ],
+            [
+                ("rbp", registers.rbp),
+                ("r8 ", registers.r8),
+                ("r9 ", registers.r9),
+            ],
+            [
+                ("r10", registers.r10),
+                ("r11", registers.r11),
+                ("r12", registers.r12),
+            ],
+            [
+                ("r13", registers.r13),
+                ("r14", registers.r14),
+                ("r15", registers.r15),
+            ],
+        ];
+        for row in rows {
+            let [(a, a_value), (b, b_value), (c, c_value)] = row;
+            writeln!(
+                f,
+                "{} {:#018x} {} {:#018x} {} {:#018x}",
+                a, a_value, b, b_value, c, c_value
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The control registers at the time of an exception.
+#[derive(Debug, Clone, Copy)]
+pub struct ControlRegisters {
+    pub cr0: u64,
+    /// The address of the last page fault.
+    pub cr2: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+}
+
+impl ControlRegisters {
+    pub fn read() -> Self {
+        let (frame, flags) = Cr3::read();
+        ControlRegisters {
+            cr0: Cr0::read_raw(),
+            cr2: Cr2::read().as_u64(),
+            cr3: frame.start_address().as_u64() | flags.bits(),
+            cr4: Cr4::read_raw(),
+        }
+    }
+}
+
+impl fmt::Display for ControlRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "cr0 {:#018x} cr2 {:#018x}", self.cr0, self.cr2)?;
+        writeln!(f, "cr3 {:#018x} cr4 {:#018x}", self.cr3, self.cr4)
+    }
+}
+
+/// Returns whether the CPU pushes an error code for the exception.
+fn has_error_code(vector: u8) -> bool {
+    matches!(vector, 8 | 10..=14 | 17 | 21 | 29 | 30)
+}
+
+/// Defines the IDT entry of an exception, which pushes a zero error code if
+/// the CPU doesn't push one, so that all exceptions leave the same frame,
+/// and the vector before continuing in [`common_entry`].
+macro_rules! exception_stub {
+    ($name:ident, $vector:literal) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push 0",
+                "push {vector}",
+                "jmp {entry}",
+                vector = const $vector,
+                entry = sym common_entry,
+            )
+        }
+    };
+    ($name:ident, $vector:literal, error_code) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            naked_asm!(
+                "push {vector}",
+                "jmp {entry}",
+                vector = const $vector,
+                entry = sym common_entry,
+            )
+        }
+    };
+}
+
+exception_stub!(divide_error, 0);
+exception_stub!(debug, 1);
+exception_stub!(non_maskable_interrupt, 2);
+exception_stub!(breakpoint, 3);
+exception_stub!(overflow, 4);
+exception_stub!(bound_range_exceeded, 5);
+exception_stub!(invalid_opcode, 6);
+exception_stub!(device_not_available, 7);
+exception_stub!(double_fault, 8, error_code);
+exception_stub!(invalid_tss, 10, error_code);
+exception_stub!(segment_not_present, 11, error_code);
+exception_stub!(stack_segment_fault, 12, error_code);
+exception_stub!(general_protection_fault, 13, error_code);
+exception_stub!(page_fault, 14, error_code);
+exception_stub!(x87_floating_point, 16);
+exception_stub!(alignment_check, 17, error_code);
+exception_stub!(machine_check, 18);
+exception_stub!(simd_floating_point, 19);
+exception_stub!(virtualization, 20);
+exception_stub!(cp_protection_exception, 21, error_code);
+exception_stub!(hv_injection_exception, 28);
+exception_stub!(vmm_communication_exception, 29, error_code);
+exception_stub!(security_exception, 30, error_code);
+
+/// Saves the general purpose registers, passes the [`ExceptionFrame`] to
+/// [`handle_exception`] and returns to the interrupted code if that returns.
+///
+/// The CPU aligns the stack before pushing its frame, which together with
+/// the vector, the error code and the 15 registers keeps it aligned for the
+/// call.
+#[unsafe(naked)]
+extern "C" fn common_entry() {
+    naked_asm!(
+        "push rax",
+        "push rbx",
+        "push rcx",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "cld",
+        "call {handler}",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        // the vector and the error code
+        "add rsp, 16",
+        "iretq",
+        handler = sym handle_exception,
+    )
+}
+
+/// Points the exception entries of `idt` to the stubs, with the NMI, double
+/// fault and page fault handlers on their own stacks.
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    let addr = |stub: extern "C" fn()| VirtAddr::new(stub as usize as u64);
+    // the stubs leave the frame handle_exception expects for every entry
+    unsafe {
+        idt.divide_error.set_handler_addr(addr(divide_error));
+        idt.debug.set_handler_addr(addr(debug));
+        idt.non_maskable_interrupt
+            .set_handler_addr(addr(non_maskable_interrupt))
+            .set_stack_index(gdt::NMI_IST_INDEX);
+        idt.breakpoint.set_handler_addr(addr(breakpoint));
+        idt.overflow.set_handler_addr(addr(overflow));
+        idt.bound_range_exceeded
+            .set_handler_addr(addr(bound_range_exceeded));
+        idt.invalid_opcode.set_handler_addr(addr(invalid_opcode));
+        idt.device_not_available
+            .set_handler_addr(addr(device_not_available));
+        idt.double_fault
+            .set_handler_addr(addr(double_fault))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(addr(invalid_tss));
+        idt.segment_not_present
+            .set_handler_addr(addr(segment_not_present));
+        idt.stack_segment_fault
+            .set_handler_addr(addr(stack_segment_fault));
+        idt.general_protection_fault
+            .set_handler_addr(addr(general_protection_fault));
+        idt.page_fault
+            .set_handler_addr(addr(page_fault))
+            .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        idt.x87_floating_point
+            .set_handler_addr(addr(x87_floating_point));
+        idt.alignment_check.set_handler_addr(addr(alignment_check));
+        idt.machine_check.set_handler_addr(addr(machine_check));
+        idt.simd_floating_point
+            .set_handler_addr(addr(simd_floating_point));
+        idt.virtualization.set_handler_addr(addr(virtualization));
+        idt.cp_protection_exception
+            .set_handler_addr(addr(cp_protection_exception));
+        idt.hv_injection_exception
+            .set_handler_addr(addr(hv_injection_exception));
+        idt.vmm_communication_exception
+            .set_handler_addr(addr(vmm_communication_exception));
+        idt.security_exception
+            .set_handler_addr(addr(security_exception));
+    }
+}
+
+/// The number of non-maskable interrupts since boot.
+pub fn nmi_count() -> u64 {
+    NMI_COUNT.load(Ordering::Relaxed)
+}
+
+/// The common handler of all exceptions.
+///
+/// Returns only for exceptions the interrupted code can continue after, like
+/// breakpoints and resolved page faults. Everything else panics with a crash
+/// report, there are no tasks yet that could be killed instead.
+extern "C" fn handle_exception(frame: &ExceptionFrame) {
+    // read first, so that nothing below can overwrite cr2
+    let control = ControlRegisters::read();
+    match frame.vector as u8 {
+        DEBUG | BREAKPOINT => {
+            trace!("EXCEPTION: {}\n{:#?}", frame.name(), frame.stack_frame);
+            return;
+        }
+        NON_MASKABLE_INTERRUPT => {
+            // an NMI can interrupt code that holds the logger lock, so it is
+            // only counted
+            NMI_COUNT.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        PAGE_FAULT => {
+            let address = VirtAddr::new_truncate(control.cr2);
+            if let Some(stack) = memory::stack::guard_page_owner(address) {
+                panic!(
+                    "stack overflow in {} (accessed {:?})\n{}{}",
+                    stack, address, frame, control
+                );
+            }
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if memory::vmm::handle_page_fault(address, error_code) {
+                return;
+            }
+        }
+        _ => {}
+    }
+    panic!("EXCEPTION: {}{}", frame, control);
+}
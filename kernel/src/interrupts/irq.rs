@@ -0,0 +1,220 @@
+use super::{PIC_1_OFFSET, PICS, apic};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{HandlerFunc, InterruptStackFrame};
+
+/// Number of ISA IRQs, which are delivered on the vectors from
+/// [`PIC_1_OFFSET`] on, through the PICs or the I/O APIC.
+pub const IRQ_COUNT: u8 = 16;
+/// The timer IRQ, raised by the PIT or, once [`apic::init`] switched to
+/// the APIC, by the local APIC timer instead.
+pub const TIMER_IRQ: u8 = 0;
+pub const KEYBOARD_IRQ: u8 = 1;
+/// The input of the primary PIC the secondary PIC is connected to.
+const CASCADE_IRQ: u8 = 2;
+
+/// Maximum number of handlers that can share an IRQ.
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// A handler of an IRQ, which is passed the IRQ number and returns whether
+/// its device raised the interrupt.
+///
+/// Runs in the interrupt handler, so it must not block or allocate, and it
+/// must not register or unregister handlers itself. The end of the
+/// interrupt is signalled after all handlers of the IRQ ran.
+pub type IrqHandler = fn(irq: u8) -> bool;
+
+/// An error returned when registering or unregistering an IRQ handler, or
+/// when masking an IRQ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The IRQ is not below [`IRQ_COUNT`].
+    InvalidIrq,
+    /// A handler with the same name is registered for the IRQ already.
+    AlreadyRegistered,
+    /// The IRQ is shared by [`MAX_SHARED_HANDLERS`] handlers already.
+    TooManyHandlers,
+    NotRegistered,
+    /// No I/O APIC handles the global system interrupt (GSI) the IRQ is
+    /// wired to.
+    Unroutable,
+}
+
+/// The handlers sharing an IRQ, with their names.
+type Handlers = [Option<(&'static str, IrqHandler)>; MAX_SHARED_HANDLERS];
+
+/// The handlers of every IRQ. Only locked with interrupts disabled, so the
+/// interrupt handlers never find them locked.
+static HANDLERS: [Mutex<Handlers>; IRQ_COUNT as usize] =
+    [const { Mutex::new([None; MAX_SHARED_HANDLERS]) }; IRQ_COUNT as usize];
+/// Interrupts per IRQ.
+static COUNTS: [AtomicU64; IRQ_COUNT as usize] = [const { AtomicU64::new(0) }; IRQ_COUNT as usize];
+/// Interrupts per IRQ that no handler claimed.
+static UNHANDLED: [AtomicU64; IRQ_COUNT as usize] =
+    [const { AtomicU64::new(0) }; IRQ_COUNT as usize];
+
+/// The IDT entries of the IRQ vectors, in IRQ order.
+pub(super) const STUBS: [HandlerFunc; IRQ_COUNT as usize] = [
+    irq_stub::<0>,
+    irq_stub::<1>,
+    irq_stub::<2>,
+    irq_stub::<3>,
+    irq_stub::<4>,
+    irq_stub::<5>,
+    irq_stub::<6>,
+    irq_stub::<7>,
+    irq_stub::<8>,
+    irq_stub::<9>,
+    irq_stub::<10>,
+    irq_stub::<11>,
+    irq_stub::<12>,
+    irq_stub::<13>,
+    irq_stub::<14>,
+    irq_stub::<15>,
+];
+
+extern "x86-interrupt" fn irq_stub<const IRQ: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(IRQ);
+}
+
+/// Runs all handlers of `irq` and signals the end of the interrupt.
+fn dispatch(irq: u8) {
+    COUNTS[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    // copied, so the handlers run without the lock
+    let handlers = *HANDLERS[usize::from(irq)].lock();
+    let mut handled = false;
+    for (_, handler) in handlers.iter().flatten() {
+        handled |= handler(irq);
+    }
+    if !handled {
+        UNHANDLED[usize::from(irq)].fetch_add(1, Ordering::Relaxed);
+    }
+    end_of_interrupt(irq);
+}
+
+/// Signals the end of the interrupt to the controller that raised it, the
+/// local APIC once [`apic::init`] switched to it and the PICs before.
+fn end_of_interrupt(irq: u8) {
+    match apic::local_apic() {
+        Some(local_apic) => local_apic.end_of_interrupt(),
+        None => unsafe { PICS.lock().notify_end_of_interrupt(vector(irq)) },
+    }
+}
+
+/// The vector `irq` is delivered on.
+pub fn vector(irq: u8) -> u8 {
+    PIC_1_OFFSET + irq
+}
+
+/// Masks all IRQs in the PICs and registers the timer and keyboard handlers.
+///
+/// Must be called after the PICs are initialized.
+pub(super) fn init(timer: IrqHandler, keyboard: IrqHandler) {
+    interrupts::without_interrupts(|| unsafe {
+        PICS.lock().write_masks(!(1 << CASCADE_IRQ), 0xff)
+    });
+    register_irq(TIMER_IRQ, "timer", timer).expect("timer IRQ registered twice");
+    register_irq(KEYBOARD_IRQ, "keyboard", keyboard).expect("keyboard IRQ registered twice");
+}
+
+/// Registers `handler` under `name` for `irq`, after the handlers that
+/// share the IRQ already, and unmasks the IRQ.
+pub fn register_irq(irq: u8, name: &'static str, handler: IrqHandler) -> Result<(), IrqError> {
+    let handlers = HANDLERS.get(usize::from(irq)).ok_or(IrqError::InvalidIrq)?;
+    interrupts::without_interrupts(|| {
+        let mut handlers = handlers.lock();
+        if handlers
+            .iter()
+            .flatten()
+            .any(|&(registered, _)| registered == name)
+        {
+            return Err(IrqError::AlreadyRegistered);
+        }
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        set_masked(irq, false)?;
+        *slot = Some((name, handler));
+        Ok(())
+    })
+}
+
+/// Removes the handler registered under `name` for `irq`, and masks the IRQ
+/// if no other handler shares it.
+pub fn unregister_irq(irq: u8, name: &'static str) -> Result<(), IrqError> {
+    let handlers = HANDLERS.get(usize::from(irq)).ok_or(IrqError::InvalidIrq)?;
+    interrupts::without_interrupts(|| {
+        let mut handlers = handlers.lock();
+        let slot = handlers
+            .iter_mut()
+            .find(|slot| slot.is_some_and(|(registered, _)| registered == name))
+            .ok_or(IrqError::NotRegistered)?;
+        *slot = None;
+        if handlers.iter().all(Option::is_none) {
+            set_masked(irq, true)?;
+        }
+        Ok(())
+    })
+}
+
+/// Returns whether any handler is registered for `irq`.
+pub fn is_registered(irq: u8) -> bool {
+    HANDLERS.get(usize::from(irq)).is_some_and(|handlers| {
+        interrupts::without_interrupts(|| handlers.lock().iter().any(Option::is_some))
+    })
+}
+
+/// Stops `irq` from being delivered until [`unmask_irq`] is called.
+pub fn mask_irq(irq: u8) -> Result<(), IrqError> {
+    interrupts::without_interrupts(|| set_masked(irq, true))
+}
+
+/// Delivers `irq` again after [`mask_irq`].
+pub fn unmask_irq(irq: u8) -> Result<(), IrqError> {
+    interrupts::without_interrupts(|| set_masked(irq, false))
+}
+
+/// Masks or unmasks `irq` in the active interrupt controller.
+fn set_masked(irq: u8, masked: bool) -> Result<(), IrqError> {
+    if irq >= IRQ_COUNT {
+        return Err(IrqError::InvalidIrq);
+    }
+    if let Some(local_apic) = apic::local_apic() {
+        // the local APIC timer replaces the PIT, which stays masked
+        return match (irq, masked) {
+            (TIMER_IRQ, _) => {
+                local_apic.set_timer_masked(masked);
+                Ok(())
+            }
+            (_, true) => apic::set_isa_irq_masked(irq, true),
+            (_, false) => apic::route_isa_irq(irq, vector(irq)),
+        };
+    }
+
+    let mut pics = PICS.lock();
+    let mut masks = unsafe { pics.read_masks() };
+    let (pic, bit) = (usize::from(irq / 8), irq % 8);
+    match masked {
+        true => masks[pic] |= 1 << bit,
+        false => masks[pic] &= !(1 << bit),
+    }
+    unsafe { pics.write_masks(masks[0], masks[1]) };
+    Ok(())
+}
+
+/// The number of interrupts `irq` raised so far.
+pub fn irq_count(irq: u8) -> u64 {
+    COUNTS
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
+
+/// The number of interrupts of `irq` that no handler claimed, like spurious
+/// interrupts.
+pub fn unhandled_irq_count(irq: u8) -> u64 {
+    UNHANDLED
+        .get(usize::from(irq))
+        .map_or(0, |count| count.load(Ordering::Relaxed))
+}
@@ -7,20 +7,39 @@
 
 extern crate alloc;
 
+pub mod acpi;
 pub mod allocator;
+pub mod backtrace;
 pub mod context;
+pub mod elf;
 pub mod framebuffer;
 pub mod gdt;
 pub mod interrupts;
 pub mod logger;
 pub mod memory;
+pub mod power;
 pub mod serial;
 pub mod task;
+pub mod time;
 // pub mod vga_buffer;
 
+use bootloader_api::config::{BootloaderConfig, Mapping};
 use core::panic::PanicInfo;
 use log::info;
 
+/// The bootloader configuration of the kernel and all test kernels.
+///
+/// Everything the bootloader maps at a dynamic address ends up in the lower
+/// part of the kernel half, so that the kernel half can be shared by all
+/// address spaces (see [`memory::address_space`]).
+pub const BOOTLOADER_CONFIG: BootloaderConfig = {
+    let mut config = BootloaderConfig::new_default();
+    config.mappings.physical_memory = Some(Mapping::Dynamic);
+    config.mappings.dynamic_range_start = Some(memory::KERNEL_HALF_START);
+    config.mappings.dynamic_range_end = Some(memory::BOOTLOADER_RANGE_END - 1);
+    config
+};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
@@ -63,6 +82,7 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
+    serial_println!("{}\n", backtrace::Backtrace::capture());
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -84,6 +104,7 @@ pub fn init() {
     unsafe {
         interrupts::PICS.lock().initialize();
     }
+    interrupts::init_irqs();
 
     info!("Enabling interrupts");
     x86_64::instructions::interrupts::enable();
@@ -1,10 +1,13 @@
+use crate::memory::vmm::{self, VmError};
+use crate::time::rtc;
 use crate::{framebuffer::FrameBufferWriter, serial_println};
 use bootloader_api::info::FrameBufferInfo;
 use conquer_once::spin::OnceCell;
 use core::fmt::{self, Write};
+use core::slice;
 use log::LevelFilter;
 use spin::Mutex;
-use x86_64::instructions::interrupts;
+use x86_64::{VirtAddr, instructions::interrupts, structures::paging::PageTableFlags};
 
 /// The global logger instance used for the `log` crate.
 pub static LOGGER: OnceCell<LockedLogger> = OnceCell::uninit();
@@ -44,16 +47,35 @@ impl log::Log for LockedLogger {
         interrupts::without_interrupts(|| {
             if let Some(framebuffer) = &self.framebuffer {
                 let mut framebuffer = framebuffer.lock();
-                writeln!(framebuffer, "{:5}: {}", record.level(), record.args()).unwrap();
+                writeln!(
+                    framebuffer,
+                    "{}{:5}: {}",
+                    Timestamp,
+                    record.level(),
+                    record.args()
+                )
+                .unwrap();
             }
 
-            serial_println!("{:5}: {}", record.level(), record.args());
+            serial_println!("{}{:5}: {}", Timestamp, record.level(), record.args());
         })
     }
 
     fn flush(&self) {}
 }
 
+/// The time of day log lines start with, once the RTC was read.
+struct Timestamp;
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match rtc::now() {
+            Some(now) => write!(f, "{:02}:{:02}:{:02} ", now.hour, now.minute, now.second),
+            None => Ok(()),
+        }
+    }
+}
+
 fn convert_level(level: LevelFilter) -> log::LevelFilter {
     match level {
         LevelFilter::Off => log::LevelFilter::Off,
@@ -73,6 +95,33 @@ pub fn init_logger(framebuffer: &'static mut [u8], info: FrameBufferInfo, log_le
     log::info!("Framebuffer info: {:?}", info);
 }
 
+/// Maps the framebuffer again through the VMM, which uses huge pages where
+/// the physical address allows it, and makes the logger draw through the
+/// new mapping.
+///
+/// The mapping of the bootloader is left alone. Must be called once the heap
+/// is initialized.
+pub fn remap_framebuffer() -> Result<(), VmError> {
+    let Some(framebuffer) = LOGGER.get().and_then(|logger| logger.framebuffer.as_ref()) else {
+        return Ok(());
+    };
+    let (start, len) = interrupts::without_interrupts(|| {
+        let writer = framebuffer.lock();
+        (
+            VirtAddr::from_ptr(writer.buffer().as_ptr()),
+            writer.buffer().len(),
+        )
+    });
+    let phys = vmm::translate(start).expect("framebuffer is not mapped");
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+
+    // the framebuffer is never handed out by the frame allocator
+    let new_start = unsafe { vmm::map_physical_region(phys, len as u64, flags)? };
+    let buffer = unsafe { slice::from_raw_parts_mut(new_start.as_mut_ptr(), len) };
+    interrupts::without_interrupts(|| unsafe { framebuffer.lock().set_buffer(buffer) });
+    Ok(())
+}
+
 impl LockedLogger {
     fn print(&self, args: core::fmt::Arguments) {
         use core::fmt::Write;
@@ -102,8 +151,11 @@ macro_rules! println {
 pub fn _print(args: fmt::Arguments) {
     use x86_64::instructions::interrupts;
 
+    // output before the logger is set up (or in tests without one) is dropped
     interrupts::without_interrupts(|| {
-        LOGGER.get().unwrap().print(args);
+        if let Some(logger) = LOGGER.get() {
+            logger.print(args);
+        }
     });
 }
 
@@ -10,16 +10,18 @@ use alloc::vec;
 use alloc::{boxed::Box, rc::Rc, vec::Vec};
 use kernel::task::executor::yield_now;
 use kernel::{
-    allocator,
-    memory::{self, BootInfoFrameAllocator},
-    task::{Task, executor::Executor, keyboard},
+    allocator, backtrace, interrupts,
+    memory::{self, BitmapFrameAllocator, GlobalFrameAllocator},
+    task::{Task, executor::Executor, keyboard, timer::sleep},
+    time,
 };
 use kernel::{logger, println};
 // use bootloader::{BootInfo, entry_point};
-use bootloader_api::config::{BootloaderConfig, Mapping};
 use bootloader_api::{BootInfo, entry_point};
 use core::panic::PanicInfo;
-use log::info;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use log::{info, warn};
 use x86_64::{VirtAddr, structures::paging::Page};
 
 pub fn serial() -> uart_16550::SerialPort {
@@ -36,6 +38,11 @@ fn panic(info: &PanicInfo) -> ! {
 
     unsafe { logger::LOGGER.get().map(|l| l.force_unlock()) };
     error!("{info}");
+    // a panic while walking the stack must not walk it again
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+    if !PANICKED.swap(true, Ordering::SeqCst) {
+        error!("{}", backtrace::Backtrace::capture());
+    }
 
     kernel::hlt_loop();
 }
@@ -52,13 +59,7 @@ fn panic(info: &PanicInfo) -> ! {
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
-pub static BOOTLOADER_CONFIG: BootloaderConfig = {
-    let mut config = BootloaderConfig::new_default();
-    config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config
-};
-
-entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+entry_point!(kernel_main, config = &kernel::BOOTLOADER_CONFIG);
 
 async fn async_number() -> u32 {
     yield_now().await;
@@ -70,6 +71,7 @@ async fn example_task() {
     loop {
         let number = async_number().await;
         // info!("async number task 1: {}", number);
+        sleep(Duration::from_secs(1)).await;
     }
 }
 
@@ -78,6 +80,7 @@ async fn example_task2() {
     loop {
         let number = async_number().await;
         // info!("async number task 2: {}", number);
+        sleep(Duration::from_secs(2)).await;
     }
 }
 
@@ -108,16 +111,46 @@ pub fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         bootloader_api::info::Optional::None => panic!("Expected phys mem offset to be Some"),
     };
 
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_regions) };
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    let merged = memory::vmm::remap_physical_memory();
+    info!(
+        "physical memory mapping: {} page tables merged into huge pages",
+        merged
+    );
+    backtrace::init(boot_info);
+    memory::report::log_memory_map();
 
     let page = Page::containing_address(VirtAddr::new(0xdeadbeef000));
-    memory::create_example_mapping(page, &mut mapper, &mut frame_allocator);
+    memory::create_example_mapping(page, &mut memory::mapper(), &mut GlobalFrameAllocator);
 
     let page_ptr: *mut u64 = page.start_address().as_mut_ptr();
     unsafe { page_ptr.offset(100).write_volatile(0x_f021_f077_f065_f04e) };
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    allocator::init_heap().expect("heap initialization failed");
+    memory::stack::register_boot_stack();
+    if let Err(err) = logger::remap_framebuffer() {
+        warn!("framebuffer not remapped: {:?}", err);
+    }
+    memory::protection::protect_kernel_image(boot_info);
+    memory::report::log_frame_usage();
+
+    match boot_info.rsdp_addr.into_option() {
+        Some(rsdp_addr) => match kernel::acpi::init(rsdp_addr) {
+            Ok(()) => {
+                kernel::acpi::log_summary();
+                if let Err(err) = interrupts::apic::init() {
+                    warn!("staying with the PICs, can't use the APIC: {:?}", err);
+                }
+            }
+            Err(err) => warn!("can't read the ACPI tables: {:?}", err),
+        },
+        None => warn!("no RSDP, staying with the PICs"),
+    }
+    time::init(time::DEFAULT_FREQUENCY);
+    info!("the time is {}", time::rtc::init());
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
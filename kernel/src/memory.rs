@@ -1,52 +1,98 @@
-use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
 // use bootloader_api::info
 // use bootloader::bootinfo::MemoryMap;
 // use bootloader::bootinfo::MemoryRegionType;
+use conquer_once::spin::OnceCell;
+use spin::{Mutex, MutexGuard};
 use x86_64::{
     PhysAddr, VirtAddr,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PhysFrame, Size4KiB,
     },
 };
 
-/// A FrameAllocator that returns usable frames from the bootloader's memory map.
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryRegions,
-    next: usize,
+pub use frame_allocator::BitmapFrameAllocator;
+
+pub mod address_space;
+pub mod cow;
+pub mod dma;
+pub mod frame_allocator;
+pub mod protection;
+pub mod report;
+pub mod stack;
+pub mod vmm;
+
+/// Start of the upper (kernel) half of the virtual address space, which is
+/// the same in every address space.
+pub const KERNEL_HALF_START: u64 = 0xffff_8000_0000_0000;
+/// End of the part of the kernel half the bootloader maps the kernel image,
+/// boot stack, physical memory and framebuffer into. The heap and the
+/// [`vmm`] window lie above it.
+pub const BOOTLOADER_RANGE_END: u64 = 0xffff_c000_0000_0000;
+
+/// The page table mapper of the running kernel.
+static MAPPER: OnceCell<Mutex<OffsetPageTable<'static>>> = OnceCell::uninit();
+/// The physical frame allocator shared by the whole kernel.
+static FRAME_ALLOCATOR: OnceCell<Mutex<BitmapFrameAllocator>> = OnceCell::uninit();
+
+/// Makes the given mapper and frame allocator available to the rest of the
+/// kernel through [`mapper`], [`frame_allocator`] and [`GlobalFrameAllocator`].
+///
+/// Panics if called more than once.
+pub fn init_global(mapper: OffsetPageTable<'static>, frame_allocator: BitmapFrameAllocator) {
+    MAPPER
+        .try_init_once(|| Mutex::new(mapper))
+        .expect("memory::init_global should only be called once");
+    FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
 }
 
-impl BootInfoFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
-    ///
-    /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryRegions) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
+/// Locks and returns the kernel's page table mapper.
+///
+/// Panics if [`init_global`] was not called yet.
+pub fn mapper() -> MutexGuard<'static, OffsetPageTable<'static>> {
+    MAPPER.get().expect("kernel mapper not initialized").lock()
+}
+
+/// Tries to lock the kernel's page table mapper without spinning.
+///
+/// Returns `None` if the mapper is not initialized yet or is currently locked.
+pub fn try_mapper() -> Option<MutexGuard<'static, OffsetPageTable<'static>>> {
+    MAPPER.get()?.try_lock()
+}
+
+/// Locks and returns the kernel's physical frame allocator.
+///
+/// Panics if [`init_global`] was not called yet.
+pub fn frame_allocator() -> MutexGuard<'static, BitmapFrameAllocator> {
+    FRAME_ALLOCATOR
+        .get()
+        .expect("frame allocator not initialized")
+        .lock()
+}
+
+/// Tries to lock the kernel's physical frame allocator without spinning.
+///
+/// Returns `None` if the frame allocator is not initialized yet or is
+/// currently locked.
+pub fn try_frame_allocator() -> Option<MutexGuard<'static, BitmapFrameAllocator>> {
+    FRAME_ALLOCATOR.get()?.try_lock()
+}
 
-    /// Returns an iterator over the usable frames specified in the memory map.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get usable regions from memory map
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.kind == MemoryRegionKind::Usable);
-        // map each region to its address range
-        let addr_ranges = usable_regions.map(|r| r.start..r.end);
-        // transform to an iterator of frame start addresses
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+/// A handle to the kernel's frame allocator that can be passed to the
+/// `Mapper` methods.
+///
+/// The allocator is only locked for the duration of a single call.
+pub struct GlobalFrameAllocator;
+
+unsafe impl<S: PageSize> FrameAllocator<S> for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        FRAME_ALLOCATOR.get()?.lock().allocate_frame()
     }
 }
 
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+impl<S: PageSize> FrameDeallocator<S> for GlobalFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        unsafe { frame_allocator().deallocate_frame(frame) }
     }
 }
 
@@ -58,6 +104,8 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 /// `physical_memory_offset`. Also, this function must be only called once
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    protection::enable_nx_and_write_protect();
+    address_space::enable_pcid();
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
@@ -91,7 +139,7 @@ pub fn create_example_mapping(
     use x86_64::structures::paging::PageTableFlags as Flags;
 
     let frame = PhysFrame::containing_address(PhysAddr::new(0xb8000));
-    let flags = Flags::PRESENT | Flags::WRITABLE;
+    let flags = Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE;
 
     let map_to_result = unsafe {
         // FIXME: this is not safe, we do it only for testing
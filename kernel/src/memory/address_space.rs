@@ -0,0 +1,454 @@
+use super::vmm::{self, VirtRange, VmError};
+use super::{GlobalFrameAllocator, KERNEL_HALF_START, cow, mapper, try_mapper};
+use core::arch::asm;
+use core::arch::x86_64::__cpuid;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::{interrupts, tlb::Pcid},
+    registers::control::{Cr3, Cr3Flags, Cr4, Cr4Flags},
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+        PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate, mapper::MapToError,
+        page_table::PageTableEntry,
+    },
+};
+
+/// End of the lower (user) half of the virtual address space.
+pub const USER_HALF_END: u64 = 0x0000_8000_0000_0000;
+/// Index of the first level 4 entry of the kernel half.
+const FIRST_KERNEL_ENTRY: usize = 256;
+/// Bit 63 of CR3: keep the cached translations of the new PCID.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Bumped whenever a kernel-half mapping is removed or restricted. Such a
+/// change is only flushed from the TLB entries of the active PCID, so every
+/// other address space has to flush its PCID when it is switched to next.
+static KERNEL_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// PCIDs in use, one bit each. PCID 0 belongs to the kernel's own table.
+static PCIDS: Mutex<[u64; 64]> = Mutex::new({
+    let mut pcids = [0; 64];
+    pcids[0] = 1;
+    pcids
+});
+
+/// Enables process-context identifiers if the CPU supports them, so that
+/// switching address spaces doesn't throw away all cached translations.
+///
+/// Returns whether PCIDs are enabled.
+pub fn enable_pcid() -> bool {
+    // CPUID.01H:ECX.PCID[bit 17]
+    let supported = __cpuid(1).ecx & (1 << 17) != 0;
+    // PCIDs can only be enabled while the active PCID is 0
+    if supported && Cr3::read_raw().1 == 0 {
+        unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::PCID)) };
+    }
+    Cr4::read().contains(Cr4Flags::PCID)
+}
+
+/// Records that the mapping of the page at `addr` was removed or restricted.
+pub(super) fn mapping_changed(addr: VirtAddr) {
+    if addr.as_u64() >= KERNEL_HALF_START {
+        KERNEL_GENERATION.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Flushes the cached translations of the active PCID.
+///
+/// Unlike [`x86_64::instructions::tlb::flush_all`], which reloads CR3 without its PCID bits and
+/// so only flushes PCID 0, this keeps the active PCID.
+pub(super) fn flush_active_pcid() {
+    let (frame, pcid) = Cr3::read_raw();
+    // bit 63 stays clear, which makes the write flush the PCID
+    unsafe { Cr3::write_raw(frame, pcid) };
+}
+
+/// A virtual address space with its own level 4 page table.
+///
+/// The kernel half is shared with the kernel's own page table and with every
+/// other address space, the user half belongs to this address space alone.
+/// Frames mapped into the user half are owned by the address space and are
+/// freed together with its page tables when it is dropped.
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4: PhysFrame,
+    phys_offset: VirtAddr,
+    pcid: Option<Pcid>,
+    /// Kernel generation when the PCID was last flushed.
+    flushed_generation: AtomicU64,
+    /// Set when the user half changed while the address space was inactive.
+    stale: AtomicBool,
+}
+
+impl AddressSpace {
+    /// Creates an address space with an empty user half.
+    pub fn new() -> Result<Self, VmError> {
+        let mut kernel = mapper();
+        let phys_offset = kernel.phys_offset();
+        let kernel_p4 = kernel.level_4_table();
+
+        // every kernel-half entry has to point to a level 3 table before it
+        // is copied, entries added later would not show up in the copies
+        for entry in kernel_p4.iter_mut().skip(FIRST_KERNEL_ENTRY) {
+            if entry.is_unused() {
+                let (frame, _) = zeroed_table(phys_offset)?;
+                entry.set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+            }
+        }
+
+        let (p4, table) = zeroed_table(phys_offset)?;
+        for (entry, kernel_entry) in table
+            .iter_mut()
+            .zip(kernel_p4.iter())
+            .skip(FIRST_KERNEL_ENTRY)
+        {
+            *entry = kernel_entry.clone();
+        }
+
+        Ok(AddressSpace {
+            p4,
+            phys_offset,
+            pcid: allocate_pcid(),
+            flushed_generation: AtomicU64::new(0),
+            // a reused PCID may still have translations of its last owner
+            stale: AtomicBool::new(true),
+        })
+    }
+
+    /// The frame of the level 4 table, i.e. the value loaded into CR3.
+    pub fn p4_frame(&self) -> PhysFrame {
+        self.p4
+    }
+
+    /// The PCID of this address space, if PCIDs are enabled and one was free.
+    pub fn pcid(&self) -> Option<Pcid> {
+        self.pcid
+    }
+
+    /// Returns whether this address space is currently loaded in CR3.
+    pub fn is_active(&self) -> bool {
+        Cr3::read_raw().0 == self.p4
+    }
+
+    /// Returns a mapper for the page tables of this address space.
+    ///
+    /// The kernel half must not be changed through it, use [`vmm`] for that.
+    pub fn mapper(&mut self) -> OffsetPageTable<'_> {
+        if !self.is_active() {
+            self.stale.store(true, Ordering::Relaxed);
+        }
+        unsafe { OffsetPageTable::new(self.table(), self.phys_offset) }
+    }
+
+    /// Maps every page of `range`, which must lie in the user half, to a
+    /// newly allocated frame.
+    pub fn map(&mut self, range: VirtRange, flags: PageTableFlags) -> Result<(), VmError> {
+        assert!(
+            range.end().as_u64() <= USER_HALF_END,
+            "{:?} is not in the user half",
+            range
+        );
+        // only 4 KiB pages, so that try_clone can share them copy-on-write
+        vmm::map_with(&mut self.mapper(), range, flags, false)
+    }
+
+    /// Unmaps every page of `range`, which must lie in the user half, and
+    /// frees the frames.
+    pub fn unmap(&mut self, range: VirtRange) {
+        assert!(
+            range.end().as_u64() <= USER_HALF_END,
+            "{:?} is not in the user half",
+            range
+        );
+        vmm::unmap_with(&mut self.mapper(), range, true);
+    }
+
+    /// Translates `addr` to the physical address it is mapped to in this
+    /// address space, if any.
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        unsafe { OffsetPageTable::new(self.table(), self.phys_offset) }.translate_addr(addr)
+    }
+
+    /// Creates a copy of this address space whose user half shares all
+    /// frames copy-on-write, so that only the pages that are written to by
+    /// one of them are copied.
+    pub fn try_clone(&mut self) -> Result<AddressSpace, VmError> {
+        let mut child = AddressSpace::new()?;
+        let mut child_mapper = child.mapper();
+        let phys_offset = self.phys_offset;
+        let active = self.is_active();
+
+        // a write fault must not see a shared frame before it is counted
+        interrupts::without_interrupts(|| {
+            for_each_user_entry(self.table(), phys_offset, &mut |page, mut entry| {
+                let PageEntry::Page4KiB(frame) = entry.kind() else {
+                    return Err(VmError::HugePage);
+                };
+                let mut flags = entry.flags();
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(cow::COPY_ON_WRITE);
+                    entry.set_flags(flags);
+                }
+                let flush = unsafe {
+                    child_mapper.map_to_with_table_flags(
+                        page,
+                        frame,
+                        flags,
+                        cow::parent_flags(flags),
+                        &mut GlobalFrameAllocator,
+                    )?
+                };
+                flush.ignore();
+                cow::add_mapping(frame);
+                Ok(())
+            })
+        })?;
+
+        // the parent's pages became read-only
+        if active {
+            flush_active_pcid();
+        } else {
+            self.stale.store(true, Ordering::Relaxed);
+        }
+        Ok(child)
+    }
+
+    /// Loads this address space into CR3.
+    ///
+    /// With PCIDs, the translations cached for this address space are kept
+    /// unless its mappings changed since it was last active.
+    ///
+    /// # Safety
+    /// The code and data used after the switch, including the stack, must be
+    /// mapped in this address space. Everything in the kernel half is.
+    pub unsafe fn switch_to(&self) {
+        let generation = KERNEL_GENERATION.load(Ordering::Relaxed);
+        match self.pcid {
+            Some(pcid) => {
+                let changed = self.stale.swap(false, Ordering::Relaxed);
+                let outdated =
+                    self.flushed_generation.swap(generation, Ordering::Relaxed) != generation;
+                let mut value = self.p4.start_address().as_u64() | u64::from(pcid.value());
+                if !changed && !outdated {
+                    value |= CR3_NO_FLUSH;
+                }
+                unsafe { asm!("mov cr3, {}", in(reg) value, options(nostack, preserves_flags)) };
+            }
+            None => unsafe { Cr3::write(self.p4, Cr3Flags::empty()) },
+        }
+    }
+
+    fn table(&mut self) -> &'static mut PageTable {
+        unsafe { &mut *(self.phys_offset + self.p4.start_address().as_u64()).as_mut_ptr() }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "dropped the active address space");
+        let phys_offset = self.phys_offset;
+        let p4 = self.table();
+
+        // free the frames mapped in the user half, then the tables themselves
+        let _ = for_each_user_entry(p4, phys_offset, &mut |_, entry| {
+            unsafe {
+                match entry.kind() {
+                    PageEntry::Page4KiB(frame) if cow::release_frame(frame) => {
+                        GlobalFrameAllocator.deallocate_frame(frame)
+                    }
+                    PageEntry::Page4KiB(_) => {}
+                    PageEntry::Page2MiB(frame) => GlobalFrameAllocator.deallocate_frame(frame),
+                    PageEntry::Page1GiB(frame) => GlobalFrameAllocator.deallocate_frame(frame),
+                }
+            }
+            Ok(())
+        });
+        for p4_entry in p4.iter().take(FIRST_KERNEL_ENTRY).filter(|e| is_table(e)) {
+            let p3 = table_at(phys_offset, p4_entry);
+            for p3_entry in p3.iter().filter(|e| is_table(e)) {
+                let p2 = table_at(phys_offset, p3_entry);
+                for p2_entry in p2.iter().filter(|e| is_table(e)) {
+                    free_table(p2_entry);
+                }
+                free_table(p3_entry);
+            }
+            free_table(p4_entry);
+        }
+        unsafe { GlobalFrameAllocator.deallocate_frame(self.p4) };
+
+        if let Some(pcid) = self.pcid {
+            let index = pcid.value() as usize;
+            PCIDS.lock()[index / 64] &= !(1 << (index % 64));
+        }
+    }
+}
+
+/// Loads the kernel's own page table into CR3.
+///
+/// # Safety
+/// See [`AddressSpace::switch_to`].
+pub unsafe fn switch_to_kernel() {
+    let p4 = kernel_p4(&mut mapper());
+    unsafe { Cr3::write(p4, Cr3Flags::empty()) };
+}
+
+/// Runs `f` with a mapper for the page table that is currently loaded in
+/// CR3, which is either the kernel's own table or that of an
+/// [`AddressSpace`].
+///
+/// Returns `None` instead of spinning if the kernel mapper is locked.
+pub(super) fn with_active_mapper<R>(f: impl FnOnce(&mut OffsetPageTable) -> R) -> Option<R> {
+    let mut kernel = try_mapper()?;
+    let active = Cr3::read_raw().0;
+    if active == kernel_p4(&mut kernel) {
+        return Some(f(&mut kernel));
+    }
+    let phys_offset = kernel.phys_offset();
+    let table = unsafe { &mut *(phys_offset + active.start_address().as_u64()).as_mut_ptr() };
+    Some(f(&mut unsafe { OffsetPageTable::new(table, phys_offset) }))
+}
+
+/// The frame of the kernel's own level 4 table.
+fn kernel_p4(kernel: &mut OffsetPageTable) -> PhysFrame {
+    let phys_offset = kernel.phys_offset().as_u64();
+    let table = kernel.level_4_table() as *mut PageTable as u64;
+    PhysFrame::containing_address(PhysAddr::new(table - phys_offset))
+}
+
+fn allocate_pcid() -> Option<Pcid> {
+    if !Cr4::read().contains(Cr4Flags::PCID) {
+        return None;
+    }
+    let mut pcids = PCIDS.lock();
+    let (word, bits) = pcids
+        .iter_mut()
+        .enumerate()
+        .find(|(_, bits)| **bits != u64::MAX)?;
+    let bit = bits.trailing_ones() as usize;
+    *bits |= 1 << bit;
+    Pcid::new((word * 64 + bit) as u16).ok()
+}
+
+/// Allocates a frame for a page table and clears it.
+fn zeroed_table(phys_offset: VirtAddr) -> Result<(PhysFrame, &'static mut PageTable), VmError> {
+    let frame: PhysFrame = GlobalFrameAllocator
+        .allocate_frame()
+        .ok_or(VmError::Map(MapToError::FrameAllocationFailed))?;
+    let table: &mut PageTable =
+        unsafe { &mut *(phys_offset + frame.start_address().as_u64()).as_mut_ptr() };
+    table.zero();
+    Ok((frame, table))
+}
+
+/// What a present page table entry maps.
+enum PageEntry {
+    Page4KiB(PhysFrame<Size4KiB>),
+    Page2MiB(PhysFrame<Size2MiB>),
+    Page1GiB(PhysFrame<Size1GiB>),
+}
+
+/// A leaf entry of a page table together with its level.
+struct LeafEntry<'a> {
+    entry: &'a mut PageTableEntry,
+    level: u8,
+}
+
+impl LeafEntry<'_> {
+    fn kind(&self) -> PageEntry {
+        let addr = self.entry.addr();
+        match self.level {
+            1 => PageEntry::Page4KiB(PhysFrame::containing_address(addr)),
+            2 => PageEntry::Page2MiB(PhysFrame::containing_address(addr)),
+            _ => PageEntry::Page1GiB(PhysFrame::containing_address(addr)),
+        }
+    }
+
+    fn flags(&self) -> PageTableFlags {
+        self.entry.flags()
+    }
+
+    fn set_flags(&mut self, flags: PageTableFlags) {
+        self.entry.set_flags(flags);
+    }
+}
+
+/// Calls `f` for every present leaf entry in the user half of `p4`, with the
+/// first page it maps.
+fn for_each_user_entry(
+    p4: &mut PageTable,
+    phys_offset: VirtAddr,
+    f: &mut dyn FnMut(Page, LeafEntry) -> Result<(), VmError>,
+) -> Result<(), VmError> {
+    let page = |indices: [usize; 4]| {
+        let addr = indices
+            .iter()
+            .fold(0, |addr, &index| (addr << 9) | index as u64)
+            << 12;
+        Page::containing_address(VirtAddr::new(addr))
+    };
+    let present = |entry: &&mut PageTableEntry| entry.flags().contains(PageTableFlags::PRESENT);
+
+    for (i4, p4_entry) in p4
+        .iter_mut()
+        .enumerate()
+        .take(FIRST_KERNEL_ENTRY)
+        .filter(|(_, e)| present(e))
+    {
+        let p3 = table_at(phys_offset, p4_entry);
+        for (i3, p3_entry) in p3.iter_mut().enumerate().filter(|(_, e)| present(e)) {
+            if !is_table(p3_entry) {
+                f(
+                    page([i4, i3, 0, 0]),
+                    LeafEntry {
+                        entry: p3_entry,
+                        level: 3,
+                    },
+                )?;
+                continue;
+            }
+            let p2 = table_at(phys_offset, p3_entry);
+            for (i2, p2_entry) in p2.iter_mut().enumerate().filter(|(_, e)| present(e)) {
+                if !is_table(p2_entry) {
+                    f(
+                        page([i4, i3, i2, 0]),
+                        LeafEntry {
+                            entry: p2_entry,
+                            level: 2,
+                        },
+                    )?;
+                    continue;
+                }
+                let p1 = table_at(phys_offset, p2_entry);
+                for (i1, p1_entry) in p1.iter_mut().enumerate().filter(|(_, e)| present(e)) {
+                    f(
+                        page([i4, i3, i2, i1]),
+                        LeafEntry {
+                            entry: p1_entry,
+                            level: 1,
+                        },
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn is_table(entry: &PageTableEntry) -> bool {
+    entry.flags().contains(PageTableFlags::PRESENT)
+        && !entry.flags().contains(PageTableFlags::HUGE_PAGE)
+}
+
+fn table_at(phys_offset: VirtAddr, entry: &PageTableEntry) -> &'static mut PageTable {
+    unsafe { &mut *(phys_offset + entry.addr().as_u64()).as_mut_ptr() }
+}
+
+fn free_table(entry: &PageTableEntry) {
+    unsafe {
+        GlobalFrameAllocator
+            .deallocate_frame(PhysFrame::<Size4KiB>::containing_address(entry.addr()))
+    };
+}
@@ -0,0 +1,231 @@
+use super::vmm::{self, VirtRange, VmError};
+use super::{GlobalFrameAllocator, address_space, mapper, try_frame_allocator};
+use alloc::collections::BTreeMap;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    instructions::interrupts,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTableFlags,
+        PhysFrame, Size4KiB, Translate,
+        mapper::{MappedFrame, TranslateResult},
+    },
+};
+
+/// Marks a read-only page whose frame is shared copy-on-write. Bit 9 is
+/// ignored by the CPU and free for the kernel to use.
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// Number of additional mappings of every shared frame, keyed by the frame's
+/// start address. A frame that isn't in here (or has a count of zero) is
+/// mapped exactly once.
+///
+/// Counts are only changed in place while handling faults and unmapping, so
+/// that neither ever touches the heap; entries that dropped to zero are
+/// removed the next time pages are shared.
+static SHARED_FRAMES: Mutex<BTreeMap<u64, usize>> = Mutex::new(BTreeMap::new());
+
+/// Maps the pages of `dst` to the frames of the corresponding pages of `src`,
+/// without copying.
+///
+/// Writable pages are made read-only and copy-on-write in both ranges, the
+/// first write to one of them gets a private copy of the frame. Pages of
+/// `src` that are not mapped are skipped.
+pub fn share(src: VirtRange, dst: VirtRange) -> Result<(), VmError> {
+    assert_eq!(
+        src.size(),
+        dst.size(),
+        "shared ranges must have the same size"
+    );
+
+    // a write to `src` must not be resolved before the frame is counted
+    interrupts::without_interrupts(|| {
+        SHARED_FRAMES.lock().retain(|_, count| *count > 0);
+        for (src_page, dst_page) in src.pages().zip(dst.pages()) {
+            // drop the mapper before taking the shared frames lock
+            let shared = share_page(&mut mapper(), src_page, dst_page)?;
+            if let Some(frame) = shared {
+                add_mapping(frame);
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Reserves a new range and shares the pages of `range` into it, giving a
+/// snapshot of the current contents that only costs memory for the pages
+/// that are written afterwards.
+pub fn snapshot(range: VirtRange) -> Result<VirtRange, VmError> {
+    let copy = vmm::reserve(range.size())?;
+    if let Err(err) = share(range, copy) {
+        vmm::unmap(copy, true);
+        vmm::release(copy);
+        return Err(err);
+    }
+    Ok(copy)
+}
+
+/// Maps `dst` to the frame of `src` copy-on-write, returning the frame, or
+/// `None` if `src` isn't mapped.
+fn share_page(
+    mapper: &mut OffsetPageTable,
+    src: Page,
+    dst: Page,
+) -> Result<Option<PhysFrame>, VmError> {
+    let (frame, flags) = match mapper.translate(src.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } => (frame, flags),
+        TranslateResult::Mapped { .. } => return Err(VmError::HugePage),
+        _ => return Ok(None),
+    };
+
+    let mut shared_flags = flags;
+    if flags.contains(PageTableFlags::WRITABLE) {
+        shared_flags.remove(PageTableFlags::WRITABLE);
+        shared_flags.insert(COPY_ON_WRITE);
+        unsafe { mapper.update_flags(src, shared_flags)?.flush() };
+        address_space::mapping_changed(src.start_address());
+    }
+    unsafe {
+        mapper
+            .map_to_with_table_flags(
+                dst,
+                frame,
+                shared_flags,
+                parent_flags(shared_flags),
+                &mut GlobalFrameAllocator,
+            )?
+            .flush()
+    };
+    Ok(Some(frame))
+}
+
+/// Drops one mapping of `frame`.
+///
+/// Returns `true` if that was the last mapping and the frame can be freed.
+pub fn release_frame(frame: PhysFrame) -> bool {
+    match SHARED_FRAMES
+        .lock()
+        .get_mut(&frame.start_address().as_u64())
+    {
+        Some(count) if *count > 0 => {
+            *count -= 1;
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Returns how many times `frame` is mapped through copy-on-write sharing,
+/// i.e. 1 for a frame that is not shared.
+pub fn mappings(frame: PhysFrame) -> usize {
+    SHARED_FRAMES
+        .lock()
+        .get(&frame.start_address().as_u64())
+        .map_or(1, |count| count + 1)
+}
+
+/// Records one more mapping of `frame`.
+pub(super) fn add_mapping(frame: PhysFrame) {
+    *SHARED_FRAMES
+        .lock()
+        .entry(frame.start_address().as_u64())
+        .or_insert(0) += 1;
+}
+
+/// The flags for the page tables above a copy-on-write page with `flags`.
+///
+/// They are always writable, so that the page only has to be made writable
+/// itself when it is copied.
+pub(super) fn parent_flags(flags: PageTableFlags) -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | (flags & PageTableFlags::USER_ACCESSIBLE)
+}
+
+/// Resolves a write fault on a copy-on-write page at `addr` by giving the
+/// page its own copy of the frame, or by simply making it writable again if
+/// it is the last mapping of the frame.
+///
+/// Works on the page table that is currently active. Returns `false` if the
+/// page is not copy-on-write or the fault can't be resolved right now
+/// because the interrupted code holds one of the locks.
+pub fn handle_write_fault(addr: VirtAddr) -> bool {
+    address_space::with_active_mapper(|mapper| copy_on_write(mapper, addr)).unwrap_or(false)
+}
+
+fn copy_on_write(mapper: &mut OffsetPageTable, addr: VirtAddr) -> bool {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let (frame, flags) = match mapper.translate(page.start_address()) {
+        TranslateResult::Mapped {
+            frame: MappedFrame::Size4KiB(frame),
+            flags,
+            ..
+        } if flags.contains(COPY_ON_WRITE) => (frame, flags),
+        _ => return false,
+    };
+    let Some(mut shared) = SHARED_FRAMES.try_lock() else {
+        return false;
+    };
+    let private_flags = (flags - COPY_ON_WRITE) | PageTableFlags::WRITABLE;
+
+    match shared.get_mut(&frame.start_address().as_u64()) {
+        Some(count) if *count > 0 => {
+            let Some(mut frames) = try_frame_allocator() else {
+                return false;
+            };
+            let Some(copy) = frames.allocate_frame() else {
+                return false;
+            };
+            unsafe {
+                let from: *const u8 =
+                    (mapper.phys_offset() + frame.start_address().as_u64()).as_ptr();
+                let to: *mut u8 =
+                    (mapper.phys_offset() + copy.start_address().as_u64()).as_mut_ptr();
+                to.copy_from_nonoverlapping(from, Size4KiB::SIZE as usize);
+            }
+            // the page is mapped, unmapping it can't fail and frees no tables
+            let (_, flush) = mapper.unmap(page).expect("copy-on-write page vanished");
+            flush.ignore();
+            let result = unsafe {
+                mapper.map_to_with_table_flags(
+                    page,
+                    copy,
+                    private_flags,
+                    parent_flags(flags),
+                    &mut *frames,
+                )
+            };
+            match result {
+                Ok(flush) => flush.flush(),
+                Err(_) => {
+                    // put the shared mapping back and let the fault be fatal
+                    unsafe {
+                        mapper
+                            .map_to_with_table_flags(
+                                page,
+                                frame,
+                                flags,
+                                parent_flags(flags),
+                                &mut *frames,
+                            )
+                            .expect("restoring a copy-on-write mapping failed")
+                            .flush();
+                        frames.deallocate_frame(copy);
+                    }
+                    return false;
+                }
+            }
+            *count -= 1;
+        }
+        _ => unsafe {
+            mapper
+                .update_flags(page, private_flags)
+                .expect("copy-on-write page vanished")
+                .flush();
+        },
+    }
+    address_space::mapping_changed(page.start_address());
+    true
+}
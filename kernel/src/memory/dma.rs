@@ -0,0 +1,129 @@
+use super::frame_allocator;
+use super::vmm::{self, VirtRange, VmError};
+use core::slice;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{PageSize, PageTableFlags, Size2MiB, Size4KiB, frame::PhysFrameRange},
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Devices limited to 32 bit addresses can only reach memory below this.
+const LOW_MEMORY_LIMIT: u64 = 0x1_0000_0000; // 4 GiB
+
+/// Requirements of the device a [`DmaBuffer`] is allocated for.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DmaOptions {
+    /// Place the buffer below 4 GiB, for devices with 32 bit DMA addresses.
+    pub below_4gib: bool,
+    /// Map the buffer with caching disabled, for devices that don't snoop
+    /// the CPU caches.
+    pub uncached: bool,
+}
+
+/// An error returned when allocating a [`DmaBuffer`].
+#[derive(Debug)]
+pub enum DmaError {
+    /// There is no free run of physical frames that is large enough.
+    OutOfContiguousMemory,
+    Vm(VmError),
+}
+
+impl From<VmError> for DmaError {
+    fn from(err: VmError) -> Self {
+        DmaError::Vm(err)
+    }
+}
+
+/// A zeroed buffer of physically contiguous frames for device DMA.
+///
+/// The device is given [`phys_addr`](Self::phys_addr), the driver accesses
+/// the buffer through [`virt_addr`](Self::virt_addr). Buffers of 2 MiB and
+/// more are aligned so that they can be mapped with huge pages. The buffer
+/// is unmapped and its frames are freed when dropped, so the device must be
+/// done with it by then.
+#[derive(Debug)]
+pub struct DmaBuffer {
+    range: VirtRange,
+    frames: PhysFrameRange,
+}
+
+impl DmaBuffer {
+    /// Allocates and maps `frames` contiguous 4 KiB frames.
+    pub fn new(frames: usize, options: DmaOptions) -> Result<Self, DmaError> {
+        let size = frames as u64 * FRAME_SIZE;
+        let align = match size >= Size2MiB::SIZE {
+            true => (Size2MiB::SIZE / FRAME_SIZE) as usize,
+            false => 1,
+        };
+        let limit = options.below_4gib.then(|| PhysAddr::new(LOW_MEMORY_LIMIT));
+        let frames = frame_allocator()
+            .allocate_contiguous_below(frames, align, limit)
+            .ok_or(DmaError::OutOfContiguousMemory)?;
+
+        let mut flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if options.uncached {
+            flags |= PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+        }
+        // the frames are ours, nothing else maps them
+        let phys = frames.start.start_address();
+        let start = match unsafe { vmm::map_physical_region(phys, size, flags) } {
+            Ok(start) => start,
+            Err(err) => {
+                unsafe { frame_allocator().deallocate_contiguous(frames) };
+                return Err(err.into());
+            }
+        };
+
+        let mut buffer = DmaBuffer {
+            range: VirtRange::new(start, size),
+            frames,
+        };
+        buffer.as_mut_slice().fill(0);
+        Ok(buffer)
+    }
+
+    /// The address the driver accesses the buffer at.
+    pub fn virt_addr(&self) -> VirtAddr {
+        self.range.start()
+    }
+
+    /// The address the device accesses the buffer at.
+    pub fn phys_addr(&self) -> PhysAddr {
+        self.frames.start.start_address()
+    }
+
+    /// The size of the buffer in bytes.
+    pub fn size(&self) -> u64 {
+        self.range.size()
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.range.start().as_ptr()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.range.start().as_mut_ptr()
+    }
+
+    /// The contents of the buffer.
+    ///
+    /// Memory the device writes to concurrently should be read with
+    /// volatile accesses through [`as_ptr`](Self::as_ptr) instead.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.size() as usize) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.as_mut_ptr(), self.size() as usize) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        vmm::unmap(self.range, false);
+        vmm::release(self.range);
+        unsafe { frame_allocator().deallocate_contiguous(self.frames) };
+    }
+}
@@ -0,0 +1,295 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::slice;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    structures::paging::{
+        FrameAllocator, FrameDeallocator, PageSize, PhysFrame, Size4KiB, frame::PhysFrameRange,
+    },
+};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+/// A physical frame allocator that tracks every 4 KiB frame with one bit.
+///
+/// A set bit means the frame is in use (or not usable at all), a cleared bit
+/// means it is free. The bitmap itself lives in the first usable region that
+/// is large enough to hold it and is accessed through the physical memory
+/// mapping.
+///
+/// Frames of any page size can be allocated and freed; a 2 MiB or 1 GiB frame
+/// is simply a naturally aligned run of 4 KiB frames.
+pub struct BitmapFrameAllocator {
+    memory_map: &'static MemoryRegions,
+    bitmap: &'static mut [u64],
+    total_frames: usize,
+    free_frames: usize,
+    /// Index of the word where the next single-frame search starts.
+    next_word: usize,
+}
+
+impl BitmapFrameAllocator {
+    /// Create a BitmapFrameAllocator from the passed memory map.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the passed memory map is valid, that all
+    /// frames marked as `USABLE` in it are really unused and that the complete
+    /// physical memory is mapped at `physical_memory_offset`. This function must
+    /// be called only once, since the bitmap is placed in usable memory.
+    pub unsafe fn init(
+        memory_map: &'static MemoryRegions,
+        physical_memory_offset: VirtAddr,
+    ) -> Self {
+        let usable_regions = || {
+            memory_map
+                .iter()
+                .filter(|r| r.kind == MemoryRegionKind::Usable)
+        };
+
+        // the bitmap only needs to cover memory up to the end of the last usable region
+        let max_addr = usable_regions().map(|r| r.end).max().unwrap_or(0);
+        let frame_count = max_addr.div_ceil(FRAME_SIZE) as usize;
+        let word_count = frame_count.div_ceil(BITS_PER_WORD);
+        let bitmap_bytes = (word_count * size_of::<u64>()) as u64;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE);
+
+        // steal the frames for the bitmap from the first region that fits it
+        let bitmap_start = usable_regions()
+            .map(|r| align_up(r.start, FRAME_SIZE)..r.end)
+            .find(|r| r.end.saturating_sub(r.start) >= bitmap_frames * FRAME_SIZE)
+            .expect("no usable region large enough for the frame bitmap")
+            .start;
+
+        let bitmap = unsafe {
+            let ptr: *mut u64 = (physical_memory_offset + bitmap_start).as_mut_ptr();
+            slice::from_raw_parts_mut(ptr, word_count)
+        };
+        // everything starts out as used, only usable regions get released
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            memory_map,
+            bitmap,
+            total_frames: 0,
+            free_frames: 0,
+            next_word: 0,
+        };
+
+        for region in usable_regions() {
+            let start = (align_up(region.start, FRAME_SIZE) / FRAME_SIZE) as usize;
+            let end = (region.end / FRAME_SIZE) as usize;
+            for frame in start..end.max(start) {
+                allocator.clear(frame);
+            }
+            allocator.total_frames += end.saturating_sub(start);
+        }
+        allocator.free_frames = allocator.total_frames;
+
+        let first_bitmap_frame = (bitmap_start / FRAME_SIZE) as usize;
+        for frame in first_bitmap_frame..first_bitmap_frame + bitmap_frames as usize {
+            allocator.set(frame);
+        }
+        allocator.free_frames -= bitmap_frames as usize;
+
+        allocator
+    }
+
+    /// Returns the memory map this allocator was created from.
+    pub fn memory_map(&self) -> &'static MemoryRegions {
+        self.memory_map
+    }
+
+    /// Returns the number of usable frames managed by this allocator.
+    pub fn total_frames(&self) -> usize {
+        self.total_frames
+    }
+
+    /// Returns the number of frames that are currently free.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Returns the number of usable frames that are currently in use.
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+
+    /// Allocates `count` physically contiguous 4 KiB frames whose first frame
+    /// is aligned to `align` frames.
+    ///
+    /// `align` must be a power of two.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<PhysFrameRange> {
+        self.allocate_contiguous_below(count, align, None)
+    }
+
+    /// Like [`allocate_contiguous`](Self::allocate_contiguous), but all
+    /// frames lie below the physical address `limit`, e.g. for devices that
+    /// can only address the first 4 GiB. `None` means no limit.
+    pub fn allocate_contiguous_below(
+        &mut self,
+        count: usize,
+        align: usize,
+        limit: Option<PhysAddr>,
+    ) -> Option<PhysFrameRange> {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        if count == 0 || count > self.free_frames {
+            return None;
+        }
+
+        let capacity = match limit {
+            Some(limit) => self
+                .frame_capacity()
+                .min((limit.as_u64() / FRAME_SIZE) as usize),
+            None => self.frame_capacity(),
+        };
+        let start = if count == 1 && align == 1 && capacity == self.frame_capacity() {
+            self.find_free_frame()?
+        } else {
+            self.find_free_run(count, align, capacity)?
+        };
+        for frame in start..start + count {
+            self.set(frame);
+        }
+        self.free_frames -= count;
+
+        Some(Self::frame_range(start, count))
+    }
+
+    /// Frees a range of frames that was returned by [`allocate_contiguous`](Self::allocate_contiguous).
+    ///
+    /// Panics if a frame lies outside of the usable memory or is free already.
+    ///
+    /// # Safety
+    /// The caller must guarantee that the frames are no longer in use.
+    pub unsafe fn deallocate_contiguous(&mut self, range: PhysFrameRange) {
+        let start = (range.start.start_address().as_u64() / FRAME_SIZE) as usize;
+        let end = (range.end.start_address().as_u64() / FRAME_SIZE) as usize;
+        // check the whole range first, so that a bad free changes nothing
+        assert!(
+            end <= self.frame_capacity() && self.is_usable(start, end),
+            "free of frames {:#x}..{:#x}, which are not all usable memory",
+            start as u64 * FRAME_SIZE,
+            end as u64 * FRAME_SIZE
+        );
+        for frame in start..end {
+            assert!(
+                self.is_used(frame),
+                "double free of frame {:#x}",
+                frame as u64 * FRAME_SIZE
+            );
+        }
+        for frame in start..end {
+            self.clear(frame);
+        }
+        self.free_frames += end - start;
+        self.next_word = self.next_word.min(start / BITS_PER_WORD);
+    }
+
+    /// Returns whether the given frame is currently allocated (or not usable).
+    pub fn is_allocated(&self, frame: PhysFrame) -> bool {
+        let index = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        index >= self.frame_capacity() || self.is_used(index)
+    }
+
+    /// Finds a single free frame, starting at the search hint.
+    fn find_free_frame(&mut self) -> Option<usize> {
+        let words = self.bitmap.len();
+        for offset in 0..words {
+            let word = (self.next_word + offset) % words;
+            let bits = self.bitmap[word];
+            if bits != u64::MAX {
+                let frame = word * BITS_PER_WORD + bits.trailing_ones() as usize;
+                if frame < self.frame_capacity() {
+                    self.next_word = word;
+                    return Some(frame);
+                }
+            }
+        }
+        None
+    }
+
+    /// Finds `count` free frames in a row below the frame index `capacity`,
+    /// starting at a multiple of `align`.
+    fn find_free_run(&self, count: usize, align: usize, capacity: usize) -> Option<usize> {
+        let mut start = 0;
+        while start + count <= capacity {
+            // skip whole words that are completely used
+            if start % BITS_PER_WORD == 0 && self.bitmap[start / BITS_PER_WORD] == u64::MAX {
+                start += BITS_PER_WORD.max(align);
+                continue;
+            }
+            match (start..start + count)
+                .rev()
+                .find(|&frame| self.is_used(frame))
+            {
+                // restart the search after the used frame, keeping the alignment
+                Some(used) => start = align_up((used + 1) as u64, align as u64) as usize,
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Returns whether every frame of `start..end` lies in a region the
+    /// memory map marks as usable.
+    fn is_usable(&self, start: usize, end: usize) -> bool {
+        // the regions don't overlap, so the frames they cover add up
+        let usable: usize = self
+            .memory_map
+            .iter()
+            .filter(|region| region.kind == MemoryRegionKind::Usable)
+            .map(|region| {
+                let first = (align_up(region.start, FRAME_SIZE) / FRAME_SIZE) as usize;
+                let last = (region.end / FRAME_SIZE) as usize;
+                last.min(end).saturating_sub(first.max(start))
+            })
+            .sum();
+        usable == end - start
+    }
+
+    fn frame_capacity(&self) -> usize {
+        self.bitmap.len() * BITS_PER_WORD
+    }
+
+    fn frame_range(start: usize, count: usize) -> PhysFrameRange {
+        let start_addr = PhysAddr::new(start as u64 * FRAME_SIZE);
+        let end_addr = PhysAddr::new((start + count) as u64 * FRAME_SIZE);
+        PhysFrame::range(
+            PhysFrame::containing_address(start_addr),
+            PhysFrame::containing_address(end_addr),
+        )
+    }
+
+    fn is_used(&self, frame: usize) -> bool {
+        self.bitmap[frame / BITS_PER_WORD] & (1 << (frame % BITS_PER_WORD)) != 0
+    }
+
+    fn set(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] |= 1 << (frame % BITS_PER_WORD);
+    }
+
+    fn clear(&mut self, frame: usize) {
+        self.bitmap[frame / BITS_PER_WORD] &= !(1 << (frame % BITS_PER_WORD));
+    }
+}
+
+unsafe impl<S: PageSize> FrameAllocator<S> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<S>> {
+        let frames = (S::SIZE / FRAME_SIZE) as usize;
+        let range = self.allocate_contiguous(frames, frames)?;
+        Some(PhysFrame::containing_address(range.start.start_address()))
+    }
+}
+
+impl<S: PageSize> FrameDeallocator<S> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<S>) {
+        let start = PhysFrame::containing_address(frame.start_address());
+        let end = PhysFrame::containing_address(frame.start_address() + S::SIZE);
+        unsafe { self.deallocate_contiguous(PhysFrame::range(start, end)) }
+    }
+}
+
+/// Align the given address `addr` upwards to alignment `align`.
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
@@ -0,0 +1,112 @@
+use crate::elf::{self, ElfFile};
+use alloc::collections::BTreeMap;
+use bootloader_api::BootInfo;
+use log::{info, warn};
+use x86_64::{
+    VirtAddr,
+    registers::{
+        control::{Cr0, Cr0Flags},
+        model_specific::{Efer, EferFlags},
+    },
+    structures::paging::{Mapper, Page, PageSize, PageTableFlags, Size4KiB},
+};
+
+/// Enables the no-execute bit in page table entries (EFER.NXE) and makes
+/// read-only pages read-only for the kernel as well (CR0.WP).
+///
+/// Must run before any page is mapped with [`PageTableFlags::NO_EXECUTE`],
+/// which is a reserved bit while NXE is off.
+pub fn enable_nx_and_write_protect() {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+}
+
+/// Remaps the loaded kernel image according to its ELF segments: code
+/// read-only and executable, read-only data read-only and non-executable,
+/// data and bss writable and non-executable. The relocation-only range
+/// (`PT_GNU_RELRO`) is made read-only too.
+///
+/// Must be called after [`super::init_global`] and once the heap is
+/// initialized.
+pub fn protect_kernel_image(boot_info: &BootInfo) {
+    let phys_offset = super::mapper().phys_offset();
+    let image = unsafe {
+        let start = phys_offset + boot_info.kernel_addr;
+        core::slice::from_raw_parts(start.as_ptr::<u8>(), boot_info.kernel_len as usize)
+    };
+    let elf = match ElfFile::parse(image) {
+        Ok(elf) => elf,
+        Err(err) => {
+            warn!("kernel image not protected, can't parse it: {:?}", err);
+            return;
+        }
+    };
+
+    // collect the flags per page first, a page that is shared by two
+    // segments must allow what both of them need
+    let mut pages: BTreeMap<Page, PageTableFlags> = BTreeMap::new();
+    let segments = || elf.program_headers().filter(|s| s.mem_size > 0);
+    for segment in segments().filter(|s| s.kind == elf::PT_LOAD) {
+        for page in segment_pages(boot_info, segment.vaddr, segment.mem_size) {
+            let flags = pages
+                .entry(page)
+                .or_insert(PageTableFlags::PRESENT | PageTableFlags::NO_EXECUTE);
+            if segment.flags & elf::PF_W != 0 {
+                flags.insert(PageTableFlags::WRITABLE);
+            }
+            if segment.flags & elf::PF_X != 0 {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+        }
+    }
+    for segment in segments().filter(|s| s.kind == elf::PT_GNU_RELRO) {
+        // only pages completely inside the range, the rest may hold data
+        let start = VirtAddr::new(boot_info.kernel_image_offset + segment.vaddr);
+        let end = start + segment.mem_size;
+        for page in Page::<Size4KiB>::range(
+            Page::containing_address(start.align_up(Size4KiB::SIZE)),
+            Page::containing_address(end),
+        ) {
+            if let Some(flags) = pages.get_mut(&page) {
+                flags.remove(PageTableFlags::WRITABLE);
+            }
+        }
+    }
+
+    // the page map is built without holding the mapper, as it may grow the heap
+    let mut mapper = super::mapper();
+    let (mut code, mut read_only, mut writable) = (0, 0, 0);
+    for (&page, &flags) in &pages {
+        let executable = !flags.contains(PageTableFlags::NO_EXECUTE);
+        if executable && flags.contains(PageTableFlags::WRITABLE) {
+            warn!("kernel page {:?} is both writable and executable", page);
+        }
+        match unsafe { mapper.update_flags(page, flags) } {
+            Ok(flush) => flush.flush(),
+            Err(err) => {
+                warn!("can't protect kernel page {:?}: {:?}", page, err);
+                continue;
+            }
+        }
+        match (executable, flags.contains(PageTableFlags::WRITABLE)) {
+            (true, _) => code += 1,
+            (false, false) => read_only += 1,
+            (false, true) => writable += 1,
+        }
+    }
+    info!(
+        "kernel image protected: {} code, {} read-only, {} writable pages",
+        code, read_only, writable
+    );
+}
+
+/// The pages covering `size` (non-zero) bytes at the link address `vaddr` of
+/// the kernel.
+fn segment_pages(boot_info: &BootInfo, vaddr: u64, size: u64) -> impl Iterator<Item = Page> {
+    let start = VirtAddr::new(boot_info.kernel_image_offset + vaddr);
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(start + size - 1u64);
+    Page::range_inclusive(first, last)
+}
@@ -0,0 +1,126 @@
+use super::frame_allocator;
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use core::fmt;
+use log::info;
+use x86_64::structures::paging::{PageSize, Size4KiB};
+
+const FRAME_SIZE: u64 = Size4KiB::SIZE;
+
+/// Totals of the memory map the bootloader handed over, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryMapStats {
+    /// Memory that was free when the kernel started.
+    pub usable: u64,
+    /// Memory used by the bootloader for the kernel image, the boot stack,
+    /// page tables and the boot info.
+    pub bootloader: u64,
+    /// Memory the firmware keeps for itself or for devices.
+    pub reserved: u64,
+    /// Number of regions in the map.
+    pub regions: usize,
+}
+
+/// Usage of the physical frames managed by the frame allocator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub used_frames: usize,
+    pub free_frames: usize,
+}
+
+impl FrameStats {
+    pub fn total_bytes(&self) -> u64 {
+        self.total_frames as u64 * FRAME_SIZE
+    }
+
+    pub fn used_bytes(&self) -> u64 {
+        self.used_frames as u64 * FRAME_SIZE
+    }
+
+    pub fn free_bytes(&self) -> u64 {
+        self.free_frames as u64 * FRAME_SIZE
+    }
+}
+
+/// A number of bytes, displayed in the largest binary unit that fits with
+/// one decimal, e.g. `127.5 MiB`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bytes(pub u64);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const UNITS: [(&str, u64); 3] = [("GiB", 1 << 30), ("MiB", 1 << 20), ("KiB", 1 << 10)];
+        match UNITS.iter().find(|&&(_, size)| self.0 >= size) {
+            Some(&(unit, size)) => {
+                let tenths = self.0 % size * 10 / size;
+                write!(f, "{}.{} {}", self.0 / size, tenths, unit)
+            }
+            None => write!(f, "{} B", self.0),
+        }
+    }
+}
+
+/// Adds up the regions of `memory_map` by kind.
+pub fn memory_map_stats(memory_map: &MemoryRegions) -> MemoryMapStats {
+    let mut stats = MemoryMapStats::default();
+    for region in memory_map.iter() {
+        let size = region.end - region.start;
+        match region.kind {
+            MemoryRegionKind::Usable => stats.usable += size,
+            MemoryRegionKind::Bootloader => stats.bootloader += size,
+            _ => stats.reserved += size,
+        }
+        stats.regions += 1;
+    }
+    stats
+}
+
+/// Returns how many frames of the frame allocator are used and free.
+pub fn frame_stats() -> FrameStats {
+    let allocator = frame_allocator();
+    FrameStats {
+        total_frames: allocator.total_frames(),
+        used_frames: allocator.used_frames(),
+        free_frames: allocator.free_frames(),
+    }
+}
+
+/// Logs every region of the bootloader's memory map with its kind and size,
+/// followed by the totals per kind.
+///
+/// Must be called after [`super::init_global`].
+pub fn log_memory_map() {
+    // the frame allocator must not be locked while logging
+    let memory_map = frame_allocator().memory_map();
+    info!("memory map:");
+    for region in memory_map.iter() {
+        info!(
+            "  {:#014x}-{:#014x} {:?} ({})",
+            region.start,
+            region.end,
+            region.kind,
+            Bytes(region.end - region.start)
+        );
+    }
+    let stats = memory_map_stats(memory_map);
+    info!(
+        "{} regions: {} usable, {} bootloader, {} reserved",
+        stats.regions,
+        Bytes(stats.usable),
+        Bytes(stats.bootloader),
+        Bytes(stats.reserved)
+    );
+}
+
+/// Logs how much of the usable physical memory is in use.
+pub fn log_frame_usage() {
+    let stats = frame_stats();
+    info!(
+        "frames: {} of {} used ({}), {} free ({})",
+        stats.used_frames,
+        stats.total_frames,
+        Bytes(stats.used_bytes()),
+        stats.free_frames,
+        Bytes(stats.free_bytes())
+    );
+}
@@ -0,0 +1,141 @@
+use super::vmm::{self, VirtRange, VmError};
+use alloc::collections::BTreeMap;
+use core::arch::asm;
+use spin::Mutex;
+use x86_64::{
+    VirtAddr,
+    structures::paging::{Page, PageSize, PageTableFlags, Size4KiB},
+};
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Default size of a [`KernelStack`], without the guard page.
+pub const DEFAULT_STACK_SIZE: u64 = 16 * PAGE_SIZE;
+
+/// How far below the current stack pointer [`register_boot_stack`] searches
+/// for the unmapped guard page of the boot stack.
+const BOOT_STACK_SEARCH_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// Guard pages of all known stacks, keyed by page start address, with the
+/// name of the stack they belong to.
+static GUARD_PAGES: Mutex<BTreeMap<u64, &'static str>> = Mutex::new(BTreeMap::new());
+
+/// A kernel stack with an unmapped guard page directly below it.
+///
+/// Running off the bottom of the stack hits the guard page, which the page
+/// fault handler reports as a stack overflow in the stack's task instead of
+/// silently corrupting whatever lies below. The stack is unmapped and its
+/// frames are freed when dropped.
+#[derive(Debug)]
+pub struct KernelStack {
+    /// The whole reservation, guard page included.
+    range: VirtRange,
+    name: &'static str,
+}
+
+impl KernelStack {
+    /// Allocates a stack of `size` bytes (rounded up to whole pages) for the
+    /// task called `name`.
+    pub fn new(name: &'static str, size: u64) -> Result<Self, VmError> {
+        let size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+        let range = vmm::reserve(size + PAGE_SIZE)?;
+        let stack = VirtRange::new(range.start() + PAGE_SIZE, size);
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        if let Err(err) = vmm::map(stack, flags) {
+            vmm::release(range);
+            return Err(err);
+        }
+
+        register_guard_page(Page::containing_address(range.start()), name);
+        Ok(KernelStack { range, name })
+    }
+
+    /// The name of the task this stack belongs to.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// The initial stack pointer, i.e. the end of the stack.
+    pub fn top(&self) -> VirtAddr {
+        self.range.end()
+    }
+
+    /// The lowest usable address of the stack.
+    pub fn bottom(&self) -> VirtAddr {
+        self.range.start() + PAGE_SIZE
+    }
+
+    /// The unmapped page below the stack.
+    pub fn guard_page(&self) -> Page {
+        Page::containing_address(self.range.start())
+    }
+
+    /// Switches to this stack and calls `entry` on it.
+    ///
+    /// # Safety
+    /// The stack must stay alive for as long as `entry` runs, which is forever
+    /// since it never returns. Nothing on the old stack may be used anymore.
+    pub unsafe fn switch_to(&self, entry: extern "C" fn() -> !) -> ! {
+        unsafe {
+            asm!(
+                "mov rsp, {top}",
+                "xor rbp, rbp",
+                "call {entry}",
+                "ud2",
+                top = in(reg) self.top().as_u64(),
+                entry = in(reg) entry,
+                options(noreturn),
+            )
+        }
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        GUARD_PAGES.lock().remove(&self.range.start().as_u64());
+        vmm::unmap(self.range, true);
+        vmm::release(self.range);
+    }
+}
+
+/// Records `page` as the guard page of the stack called `name`.
+pub fn register_guard_page(page: Page, name: &'static str) {
+    GUARD_PAGES
+        .lock()
+        .insert(page.start_address().as_u64(), name);
+}
+
+/// Finds the guard page the bootloader left below the boot stack and
+/// registers it under the name `"boot"`.
+///
+/// Must be called on the boot stack, once the heap is initialized. Returns
+/// the guard page, or `None` if no unmapped page was found near the stack.
+pub fn register_boot_stack() -> Option<Page> {
+    let rsp: u64;
+    unsafe { asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack, preserves_flags)) };
+
+    // the boot stack is mapped contiguously, the first unmapped page below
+    // the stack pointer is its guard page
+    let mut page = Page::<Size4KiB>::containing_address(VirtAddr::new(rsp));
+    let lowest = VirtAddr::new(rsp.saturating_sub(BOOT_STACK_SEARCH_LIMIT));
+    while page.start_address() > lowest {
+        page -= 1;
+        if vmm::translate(page.start_address()).is_none() {
+            register_guard_page(page, "boot");
+            return Some(page);
+        }
+    }
+    None
+}
+
+/// Returns the name of the stack whose guard page contains `addr`.
+///
+/// Called from the page fault handler, so it gives up instead of spinning if
+/// the registry is locked by the interrupted code.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<&'static str> {
+    let page = Page::<Size4KiB>::containing_address(addr);
+    GUARD_PAGES
+        .try_lock()?
+        .get(&page.start_address().as_u64())
+        .copied()
+}
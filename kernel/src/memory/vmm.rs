@@ -0,0 +1,826 @@
+use super::{
+    GlobalFrameAllocator, address_space, cow, frame_allocator, mapper, try_frame_allocator,
+    try_mapper,
+};
+use alloc::collections::BTreeMap;
+use bootloader_api::info::MemoryRegionKind;
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::__cpuid;
+use spin::Mutex;
+use x86_64::{
+    PhysAddr, VirtAddr,
+    instructions::tlb,
+    structures::{
+        idt::PageFaultErrorCode,
+        paging::{
+            FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+            PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
+            mapper::{FlagUpdateError, MapToError, TranslateResult, UnmapError},
+            page::PageRange,
+            page_table::PageTableEntry,
+        },
+    },
+};
+
+/// Start of the virtual address window handed out by [`reserve`].
+pub const VMM_START: u64 = 0xffff_d000_0000_0000;
+/// Size of the virtual address window handed out by [`reserve`].
+pub const VMM_SIZE: u64 = 0x100_0000_0000; // 1 TiB
+
+const PAGE_SIZE: u64 = Size4KiB::SIZE;
+
+/// Free parts of the reservation window, keyed by start address.
+static FREE_RANGES: Mutex<Option<BTreeMap<u64, u64>>> = Mutex::new(None);
+
+/// Ranges that are backed on first touch, keyed by start address, with their
+/// size and the flags their pages are mapped with.
+static LAZY_REGIONS: Mutex<BTreeMap<u64, (u64, PageTableFlags)>> = Mutex::new(BTreeMap::new());
+
+/// Whether the CPU supports 1 GiB pages, see [`huge_page_sizes`].
+static GIGABYTE_PAGES: OnceCell<bool> = OnceCell::uninit();
+
+/// A page-aligned range of virtual memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VirtRange {
+    start: VirtAddr,
+    size: u64,
+}
+
+impl VirtRange {
+    /// Creates a range of `size` bytes starting at `start`.
+    ///
+    /// Panics if `start` or `size` are not page aligned.
+    pub fn new(start: VirtAddr, size: u64) -> Self {
+        assert!(
+            start.is_aligned(PAGE_SIZE),
+            "range start must be page aligned"
+        );
+        assert!(
+            size.is_multiple_of(PAGE_SIZE),
+            "range size must be a multiple of the page size"
+        );
+        VirtRange { start, size }
+    }
+
+    pub fn start(&self) -> VirtAddr {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtAddr {
+        self.start + self.size
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn contains(&self, addr: VirtAddr) -> bool {
+        self.start <= addr && addr < self.end()
+    }
+
+    /// Returns the 4 KiB pages of this range.
+    pub fn pages(&self) -> PageRange {
+        Page::range(
+            Page::containing_address(self.start),
+            Page::containing_address(self.end()),
+        )
+    }
+}
+
+/// An error returned by the virtual memory manager.
+#[derive(Debug)]
+pub enum VmError {
+    /// The reservation window has no free range of the requested size.
+    OutOfVirtualMemory,
+    /// The kernel mapper is locked by the caller itself.
+    MapperBusy,
+    /// The operation only works on 4 KiB pages, or only on whole huge pages,
+    /// but found a huge page.
+    HugePage,
+    Map(MapToError<Size4KiB>),
+    Unmap(UnmapError),
+    FlagUpdate(FlagUpdateError),
+}
+
+impl<S: PageSize> From<MapToError<S>> for VmError {
+    fn from(err: MapToError<S>) -> Self {
+        VmError::Map(match err {
+            MapToError::FrameAllocationFailed => MapToError::FrameAllocationFailed,
+            MapToError::ParentEntryHugePage => MapToError::ParentEntryHugePage,
+            // a huge page is reported by its first 4 KiB frame
+            MapToError::PageAlreadyMapped(frame) => {
+                MapToError::PageAlreadyMapped(PhysFrame::containing_address(frame.start_address()))
+            }
+        })
+    }
+}
+
+impl From<UnmapError> for VmError {
+    fn from(err: UnmapError) -> Self {
+        VmError::Unmap(err)
+    }
+}
+
+impl From<FlagUpdateError> for VmError {
+    fn from(err: FlagUpdateError) -> Self {
+        VmError::FlagUpdate(err)
+    }
+}
+
+/// Reserves `size` bytes (rounded up to whole pages) of unused kernel virtual
+/// address space. Nothing is mapped yet.
+///
+/// Ranges of at least a huge page are aligned to the largest huge page that
+/// fits, so that [`map_huge`] can use huge pages for them.
+pub fn reserve(size: u64) -> Result<VirtRange, VmError> {
+    reserve_aligned(size, largest_page_size(size))
+}
+
+/// Like [`reserve`], but the start of the range is aligned to `align`, which
+/// must be a power of two of at least the page size.
+pub fn reserve_aligned(size: u64, align: u64) -> Result<VirtRange, VmError> {
+    assert!(align.is_power_of_two() && align >= PAGE_SIZE);
+    let size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+
+    let mut free_ranges = FREE_RANGES.lock();
+    let free_ranges = free_ranges.get_or_insert_with(|| BTreeMap::from([(VMM_START, VMM_SIZE)]));
+
+    let (free_start, free_size, start) = free_ranges
+        .iter()
+        .map(|(&free_start, &free_size)| {
+            let start = free_start.next_multiple_of(align);
+            (free_start, free_size, start)
+        })
+        .find(|&(free_start, free_size, start)| start + size <= free_start + free_size)
+        .ok_or(VmError::OutOfVirtualMemory)?;
+
+    // split the free range into the parts in front of and behind the reservation
+    free_ranges.remove(&free_start);
+    if start > free_start {
+        free_ranges.insert(free_start, start - free_start);
+    }
+    let end = start + size;
+    if end < free_start + free_size {
+        free_ranges.insert(end, free_start + free_size - end);
+    }
+
+    Ok(VirtRange::new(VirtAddr::new(start), size))
+}
+
+/// Gives a range returned by [`reserve`] back to the reservation window.
+///
+/// The range must be unmapped before.
+pub fn release(range: VirtRange) {
+    let mut free_ranges = FREE_RANGES.lock();
+    let free_ranges = free_ranges
+        .as_mut()
+        .expect("release of a range that was never reserved");
+
+    let mut start = range.start().as_u64();
+    let mut size = range.size();
+
+    // merge with the free ranges directly in front of and behind the range
+    if let Some((&prev_start, &prev_size)) = free_ranges.range(..start).next_back() {
+        assert!(
+            prev_start + prev_size <= start,
+            "double release of {:?}",
+            range
+        );
+        if prev_start + prev_size == start {
+            free_ranges.remove(&prev_start);
+            start = prev_start;
+            size += prev_size;
+        }
+    }
+    if let Some(next_size) = free_ranges.remove(&(start + size)) {
+        size += next_size;
+    }
+    free_ranges.insert(start, size);
+}
+
+/// Maps every page of `range` to a newly allocated frame.
+///
+/// Only 4 KiB pages are used, so that the range can be shared copy-on-write
+/// with [`cow::snapshot`]. If mapping fails half way, the pages mapped so far
+/// are unmapped again.
+pub fn map(range: VirtRange, flags: PageTableFlags) -> Result<(), VmError> {
+    map_with(&mut mapper(), range, flags, false)
+}
+
+/// Like [`map`], but fails with [`VmError::MapperBusy`] instead of spinning
+/// if the kernel mapper is locked, e.g. by the code that is calling this.
+pub fn try_map(range: VirtRange, flags: PageTableFlags) -> Result<(), VmError> {
+    let mut mapper = try_mapper().ok_or(VmError::MapperBusy)?;
+    map_with(&mut mapper, range, flags, false)
+}
+
+/// Like [`map`], but parts of the range that are aligned to a huge page are
+/// mapped with 2 MiB or 1 GiB pages, unless physical memory is too
+/// fragmented for a frame of that size.
+///
+/// Huge pages can't be shared copy-on-write.
+pub fn map_huge(range: VirtRange, flags: PageTableFlags) -> Result<(), VmError> {
+    map_with(&mut mapper(), range, flags, true)
+}
+
+/// Like [`map_huge`], but fails with [`VmError::MapperBusy`] instead of
+/// spinning if the kernel mapper is locked.
+pub fn try_map_huge(range: VirtRange, flags: PageTableFlags) -> Result<(), VmError> {
+    let mut mapper = try_mapper().ok_or(VmError::MapperBusy)?;
+    map_with(&mut mapper, range, flags, true)
+}
+
+/// Maps `range` to new frames in `mapper`, with huge pages where possible if
+/// `huge_pages` is set and with 4 KiB pages only otherwise.
+pub(super) fn map_with(
+    mapper: &mut OffsetPageTable,
+    range: VirtRange,
+    flags: PageTableFlags,
+    huge_pages: bool,
+) -> Result<(), VmError> {
+    let mut mapped = 0;
+    while mapped < range.size() {
+        let addr = range.start() + mapped;
+        let mut size = match huge_pages {
+            true => page_size_at(addr, None, range.size() - mapped),
+            false => PAGE_SIZE,
+        };
+        let result = loop {
+            match allocate_frame(size) {
+                Some(frame) => {
+                    // the frame may still hold whatever its last user left there
+                    unsafe { zero_frame(mapper.phys_offset(), frame, size) };
+                    let result = unsafe { map_page(mapper, addr, frame, size, flags) };
+                    if result.is_err() {
+                        unsafe { deallocate_frame(frame, size) };
+                    }
+                    break result;
+                }
+                // no free frame of that size, try the next smaller one
+                None if size > PAGE_SIZE => size /= 512,
+                None => break Err(MapToError::<Size4KiB>::FrameAllocationFailed.into()),
+            }
+        };
+        if let Err(err) = result {
+            unmap_with(mapper, VirtRange::new(range.start(), mapped), true);
+            return Err(err);
+        }
+        mapped += size;
+    }
+    Ok(())
+}
+
+/// Registers `range` as lazily backed: nothing is mapped now, instead the
+/// page fault handler maps a zeroed frame with `flags` into each page the
+/// first time it is touched.
+///
+/// Pages that are never touched don't use any physical memory.
+pub fn map_lazy(range: VirtRange, flags: PageTableFlags) {
+    let mut regions = LAZY_REGIONS.lock();
+    if let Some((&start, &(size, _))) = regions.range(..range.end().as_u64()).next_back() {
+        assert!(
+            start + size <= range.start().as_u64(),
+            "lazy region {:?} overlaps an existing one",
+            range
+        );
+    }
+    regions.insert(
+        range.start().as_u64(),
+        (range.size(), flags | PageTableFlags::PRESENT),
+    );
+}
+
+/// Tries to resolve a page fault at `addr`, either by backing the page if it
+/// belongs to a range registered with [`map_lazy`] or by copying a
+/// copy-on-write page on the first write (see [`super::cow`]).
+///
+/// Returns `false` if the fault is neither, or if it can't be resolved right
+/// now because the interrupted code holds one of the needed locks.
+pub fn handle_page_fault(addr: VirtAddr, error_code: PageFaultErrorCode) -> bool {
+    // faults on present pages are access violations, not missing pages
+    if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+        return error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE)
+            && cow::handle_write_fault(addr);
+    }
+    let Some(flags) = lazy_flags(addr) else {
+        return false;
+    };
+    let Some(mut mapper) = try_mapper() else {
+        return false;
+    };
+    let Some(mut frames) = try_frame_allocator() else {
+        return false;
+    };
+
+    let page = Page::<Size4KiB>::containing_address(addr);
+    let Some(frame) = frames.allocate_frame() else {
+        return false;
+    };
+    unsafe { zero_frame(mapper.phys_offset(), frame.start_address(), PAGE_SIZE) };
+    match unsafe { mapper.map_to(page, frame, flags, &mut *frames) } {
+        Ok(flush) => {
+            flush.flush();
+            true
+        }
+        Err(_) => {
+            unsafe { frames.deallocate_frame(frame) };
+            false
+        }
+    }
+}
+
+/// Fills the `size` bytes of the frame at `frame` with zeroes through the
+/// physical memory mapping at `phys_offset`.
+///
+/// # Safety
+/// The frame must not be in use.
+unsafe fn zero_frame(phys_offset: VirtAddr, frame: PhysAddr, size: u64) {
+    let frame_ptr: *mut u8 = (phys_offset + frame.as_u64()).as_mut_ptr();
+    unsafe { frame_ptr.write_bytes(0, size as usize) };
+}
+
+/// Returns the flags of the lazy range containing `addr`, without spinning
+/// on the registry lock.
+fn lazy_flags(addr: VirtAddr) -> Option<PageTableFlags> {
+    let regions = LAZY_REGIONS.try_lock()?;
+    let (&start, &(size, flags)) = regions.range(..=addr.as_u64()).next_back()?;
+    (addr.as_u64() < start + size).then_some(flags)
+}
+
+/// Maps `range` to the physical memory starting at `phys`.
+///
+/// Huge pages are used wherever both the virtual and the physical address
+/// are aligned to one, see [`reserve_for_physical`].
+///
+/// # Safety
+/// The caller must make sure that the physical memory may be accessed through
+/// the new mapping, i.e. that it is not in use by something else or that the
+/// aliasing is intended (as for device memory).
+pub unsafe fn map_physical(
+    range: VirtRange,
+    phys: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), VmError> {
+    assert!(
+        phys.is_aligned(PAGE_SIZE),
+        "physical address must be page aligned"
+    );
+    let mut mapper = mapper();
+    let mut mapped = 0;
+    while mapped < range.size() {
+        let (addr, frame) = (range.start() + mapped, phys + mapped);
+        let size = page_size_at(addr, Some(frame), range.size() - mapped);
+        if let Err(err) = unsafe { map_page(&mut mapper, addr, frame, size, flags) } {
+            unmap_with(&mut mapper, VirtRange::new(range.start(), mapped), false);
+            return Err(err);
+        }
+        mapped += size;
+    }
+    Ok(())
+}
+
+/// Reserves a range for mapping `size` bytes of physical memory starting at
+/// the page aligned address `phys`.
+///
+/// The range starts at the same offset from a huge page boundary as `phys`,
+/// so that [`map_physical`] can use huge pages for the aligned middle part.
+pub fn reserve_for_physical(phys: PhysAddr, size: u64) -> Result<VirtRange, VmError> {
+    let size = size.div_ceil(PAGE_SIZE).max(1) * PAGE_SIZE;
+    let align = largest_page_size(size);
+    let offset = phys.as_u64() % align;
+    let range = reserve_aligned(offset + size, align)?;
+    if offset > 0 {
+        release(VirtRange::new(range.start(), offset));
+    }
+    Ok(VirtRange::new(range.start() + offset, size))
+}
+
+/// Maps `size` bytes of device memory starting at `phys` into a newly
+/// reserved range with caching disabled.
+///
+/// Returns the virtual address that corresponds to `phys`.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, VmError> {
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    // device memory is never handed out by the frame allocator
+    unsafe { map_physical_region(phys, size, flags) }
+}
+
+/// Maps `size` bytes of physical memory starting at `phys`, which doesn't
+/// have to be page aligned, into a newly reserved range.
+///
+/// Returns the virtual address that corresponds to `phys`.
+///
+/// # Safety
+/// Same as for [`map_physical`].
+pub unsafe fn map_physical_region(
+    phys: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<VirtAddr, VmError> {
+    let phys_start = phys.align_down(PAGE_SIZE);
+    let offset = phys - phys_start;
+    let range = reserve_for_physical(phys_start, offset + size)?;
+    if let Err(err) = unsafe { map_physical(range, phys_start, flags) } {
+        release(range);
+        return Err(err);
+    }
+    Ok(range.start() + offset)
+}
+
+/// Unmaps every mapped page of `range` and frees the page tables that became
+/// empty. If `free_frames` is set, the frames the pages were mapped to are
+/// given back to the frame allocator.
+///
+/// Lazy ranges starting inside `range` are unregistered as well.
+pub fn unmap(range: VirtRange, free_frames: bool) {
+    LAZY_REGIONS
+        .lock()
+        .retain(|&start, _| !range.contains(VirtAddr::new(start)));
+    unmap_with(&mut mapper(), range, free_frames);
+}
+
+pub(super) fn unmap_with(mapper: &mut OffsetPageTable, range: VirtRange, free_frames: bool) {
+    if range.size() == 0 {
+        return;
+    }
+    let mut addr = range.start();
+    while addr < range.end() {
+        // pages that are not mapped are simply skipped
+        let Some((start, frame, size, _)) = mapped_page(mapper, addr) else {
+            addr += PAGE_SIZE;
+            continue;
+        };
+        assert!(
+            range.contains(start) && start + size <= range.end(),
+            "{:?} only covers part of the huge page at {:?}",
+            range,
+            start
+        );
+        unmap_page(mapper, start, size).expect("mapped page vanished");
+        address_space::mapping_changed(start);
+        // frames shared copy-on-write are freed with their last mapping, huge
+        // pages are never shared
+        if free_frames
+            && (size != PAGE_SIZE || cow::release_frame(PhysFrame::containing_address(frame)))
+        {
+            unsafe { deallocate_frame(frame, size) };
+        }
+        addr = start + size;
+    }
+    free_empty_tables(mapper, range);
+}
+
+/// Frees the level 1 and level 2 page tables below `range` that no longer
+/// map anything.
+///
+/// Level 3 tables are kept, so that the level 4 entries of the kernel half
+/// stay valid in every address space that shares them.
+fn free_empty_tables(mapper: &mut OffsetPageTable, range: VirtRange) {
+    const LEVEL_3_SPAN: u64 = Size1GiB::SIZE;
+
+    let offset = mapper.phys_offset();
+    let table = |entry: &PageTableEntry| unsafe {
+        &mut *(offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>()
+    };
+    let is_table = |entry: &PageTableEntry| {
+        entry.flags().contains(PageTableFlags::PRESENT)
+            && !entry.flags().contains(PageTableFlags::HUGE_PAGE)
+    };
+    let is_empty = |table: &PageTable| table.iter().all(|entry| entry.is_unused());
+
+    let (start, end) = (range.start().as_u64(), range.end().as_u64());
+    let p4 = mapper.level_4_table();
+    let mut freed = false;
+    let mut chunk = start - start % LEVEL_3_SPAN;
+    while chunk < end {
+        let addr = VirtAddr::new(chunk.max(start));
+        let p4_entry = &p4[addr.p4_index()];
+        if is_table(p4_entry) {
+            let p3_entry = &mut table(p4_entry)[addr.p3_index()];
+            if is_table(p3_entry) {
+                let p2 = table(p3_entry);
+                let last = VirtAddr::new(chunk.saturating_add(LEVEL_3_SPAN).min(end) - 1);
+                for index in u16::from(addr.p2_index())..=u16::from(last.p2_index()) {
+                    let p2_entry = &mut p2[index as usize];
+                    if is_table(p2_entry) && is_empty(table(p2_entry)) {
+                        let frame = PhysFrame::<Size4KiB>::containing_address(p2_entry.addr());
+                        p2_entry.set_unused();
+                        unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                        freed = true;
+                    }
+                }
+                if is_empty(p2) {
+                    let frame = PhysFrame::<Size4KiB>::containing_address(p3_entry.addr());
+                    p3_entry.set_unused();
+                    unsafe { GlobalFrameAllocator.deallocate_frame(frame) };
+                    freed = true;
+                }
+            }
+        }
+        match chunk.checked_add(LEVEL_3_SPAN) {
+            Some(next) => chunk = next,
+            None => break,
+        }
+    }
+
+    // the CPU may still cache the freed tables
+    if freed {
+        address_space::flush_active_pcid();
+    }
+}
+
+/// Remaps the physical memory mapping of the bootloader with the largest
+/// supported pages, wherever it used a page table of smaller ones.
+///
+/// Only page tables whose entries all map physical memory linearly with the
+/// same flags are replaced, so the translations stay exactly the same.
+/// Returns the number of page tables replaced by a huge page.
+pub fn remap_physical_memory() -> usize {
+    let memory_map = frame_allocator().memory_map();
+    let end = memory_map
+        .iter()
+        .map(|region| region.end)
+        .max()
+        .unwrap_or(0);
+    let mut mapper = mapper();
+    let offset = mapper.phys_offset();
+
+    let mut merged = 0;
+    // smallest first, so that merged 2 MiB pages can be merged again
+    for page_size in [Size2MiB::SIZE, Size1GiB::SIZE] {
+        if !huge_page_sizes().any(|size| size == page_size) {
+            continue;
+        }
+        let mut phys = 0;
+        while phys < end {
+            let addr = offset + phys;
+            if let Some(table) =
+                merge_into_huge_page(&mut mapper, addr, PhysAddr::new(phys), page_size)
+            {
+                merged += 1;
+                // the bootloader's tables are not managed by the frame allocator
+                let usable = memory_map.iter().any(|region| {
+                    region.kind == MemoryRegionKind::Usable
+                        && (region.start..region.end).contains(&table.start_address().as_u64())
+                });
+                if usable {
+                    unsafe { GlobalFrameAllocator.deallocate_frame(table) };
+                }
+            }
+            phys += page_size;
+        }
+    }
+    merged
+}
+
+/// Replaces the page table that maps the `page_size` bytes at `addr` with a
+/// single huge page mapping them to `phys`, if every entry of the table maps
+/// its part linearly with the same flags.
+///
+/// Returns the frame of the replaced table.
+fn merge_into_huge_page(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+    phys: PhysAddr,
+    page_size: u64,
+) -> Option<PhysFrame> {
+    let offset = mapper.phys_offset();
+    let table = |entry: &PageTableEntry| unsafe {
+        &mut *(offset + entry.addr().as_u64()).as_mut_ptr::<PageTable>()
+    };
+    let is_table = |entry: &PageTableEntry| {
+        entry.flags().contains(PageTableFlags::PRESENT)
+            && !entry.flags().contains(PageTableFlags::HUGE_PAGE)
+    };
+
+    let p4_entry = &mapper.level_4_table()[addr.p4_index()];
+    if !is_table(p4_entry) {
+        return None;
+    }
+    let mut entry = &mut table(p4_entry)[addr.p3_index()];
+    if page_size == Size2MiB::SIZE {
+        if !is_table(entry) {
+            return None;
+        }
+        entry = &mut table(entry)[addr.p2_index()];
+    }
+    if !is_table(entry) {
+        return None;
+    }
+
+    // in a level 1 table the huge page bit selects a PAT entry instead, and
+    // the PAT bit of a huge page lies in its address, so mappings with
+    // another memory type never match
+    let ignored = PageTableFlags::ACCESSED | PageTableFlags::DIRTY;
+    let entries = table(entry);
+    let flags = entries[0].flags() - ignored;
+    let child_size = page_size / 512;
+    let linear = entries.iter().enumerate().all(|(index, child)| {
+        child.flags() - ignored == flags && child.addr() == phys + index as u64 * child_size
+    });
+    if !linear
+        || !flags.contains(PageTableFlags::PRESENT)
+        || flags.contains(PageTableFlags::HUGE_PAGE) != (child_size != PAGE_SIZE)
+    {
+        return None;
+    }
+
+    let frame = PhysFrame::containing_address(entry.addr());
+    entry.set_addr(phys, flags | PageTableFlags::HUGE_PAGE);
+    // INVLPG also drops the cached entries that point to the old table
+    tlb::flush(addr);
+    address_space::mapping_changed(addr);
+    Some(frame)
+}
+
+/// Changes the flags of every page in `range`.
+///
+/// Fails with [`VmError::HugePage`] if the range covers only part of a huge
+/// page.
+///
+/// # Safety
+/// Changing the flags can make memory that is still in use inaccessible or
+/// writable, the caller must make sure that this doesn't break any
+/// assumptions of the code using it.
+pub unsafe fn protect(range: VirtRange, flags: PageTableFlags) -> Result<(), VmError> {
+    let mut mapper = mapper();
+    let mut addr = range.start();
+    while addr < range.end() {
+        let (start, _, size, _) =
+            mapped_page(&mut mapper, addr).ok_or(FlagUpdateError::PageNotMapped)?;
+        if !range.contains(start) || start + size > range.end() {
+            return Err(VmError::HugePage);
+        }
+        unsafe { update_page_flags(&mut mapper, start, size, flags)? };
+        address_space::mapping_changed(start);
+        addr = start + size;
+    }
+    Ok(())
+}
+
+/// Translates the given virtual address to the physical address it is
+/// mapped to, if any.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    mapper().translate_addr(addr)
+}
+
+/// Returns the start address, frame, size and flags of the page that maps
+/// `addr`, whatever its size.
+fn mapped_page(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+) -> Option<(VirtAddr, PhysAddr, u64, PageTableFlags)> {
+    match mapper.translate(addr) {
+        TranslateResult::Mapped {
+            frame,
+            offset,
+            flags,
+        } => Some((addr - offset, frame.start_address(), frame.size(), flags)),
+        _ => None,
+    }
+}
+
+/// The supported huge page sizes, largest first.
+///
+/// 1 GiB pages are optional and only used if CPUID reports them.
+fn huge_page_sizes() -> impl Iterator<Item = u64> {
+    // CPUID.80000001H:EDX.Page1GB[bit 26]
+    let gigabyte_pages = *GIGABYTE_PAGES.get_or_init(|| __cpuid(0x8000_0001).edx & (1 << 26) != 0);
+    gigabyte_pages
+        .then_some(Size1GiB::SIZE)
+        .into_iter()
+        .chain([Size2MiB::SIZE])
+}
+
+/// The largest supported page size that is not larger than `size`.
+fn largest_page_size(size: u64) -> u64 {
+    huge_page_sizes()
+        .find(|&page_size| page_size <= size)
+        .unwrap_or(PAGE_SIZE)
+}
+
+/// The largest page size that fits into `remaining` bytes and that `addr`,
+/// and `phys` if the frame is given, are aligned to.
+fn page_size_at(addr: VirtAddr, phys: Option<PhysAddr>, remaining: u64) -> u64 {
+    huge_page_sizes()
+        .find(|&size| {
+            size <= remaining
+                && addr.is_aligned(size)
+                && phys.is_none_or(|phys| phys.is_aligned(size))
+        })
+        .unwrap_or(PAGE_SIZE)
+}
+
+fn allocate_frame(size: u64) -> Option<PhysAddr> {
+    match size {
+        Size1GiB::SIZE => allocate_sized::<Size1GiB>(),
+        Size2MiB::SIZE => allocate_sized::<Size2MiB>(),
+        _ => allocate_sized::<Size4KiB>(),
+    }
+}
+
+fn allocate_sized<S: PageSize>() -> Option<PhysAddr> {
+    let frame: PhysFrame<S> = GlobalFrameAllocator.allocate_frame()?;
+    Some(frame.start_address())
+}
+
+unsafe fn deallocate_frame(frame: PhysAddr, size: u64) {
+    unsafe {
+        match size {
+            Size1GiB::SIZE => GlobalFrameAllocator
+                .deallocate_frame(PhysFrame::<Size1GiB>::containing_address(frame)),
+            Size2MiB::SIZE => GlobalFrameAllocator
+                .deallocate_frame(PhysFrame::<Size2MiB>::containing_address(frame)),
+            _ => GlobalFrameAllocator
+                .deallocate_frame(PhysFrame::<Size4KiB>::containing_address(frame)),
+        }
+    }
+}
+
+/// Maps the page of `size` bytes at `addr` to the frame at `frame`, both of
+/// which must be aligned to `size`.
+unsafe fn map_page(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+    frame: PhysAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), VmError> {
+    unsafe {
+        match size {
+            Size1GiB::SIZE => map_sized::<Size1GiB>(mapper, addr, frame, flags),
+            Size2MiB::SIZE => map_sized::<Size2MiB>(mapper, addr, frame, flags),
+            _ => map_sized::<Size4KiB>(mapper, addr, frame, flags),
+        }
+    }
+}
+
+unsafe fn map_sized<'a, S: PageSize>(
+    mapper: &mut OffsetPageTable<'a>,
+    addr: VirtAddr,
+    frame: PhysAddr,
+    flags: PageTableFlags,
+) -> Result<(), VmError>
+where
+    OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(addr).expect("page not aligned to its size");
+    let frame = PhysFrame::<S>::from_start_address(frame).expect("frame not aligned to its size");
+    unsafe { mapper.map_to(page, frame, flags, &mut GlobalFrameAllocator)? }.flush();
+    Ok(())
+}
+
+/// Unmaps the page of `size` bytes starting at `addr`.
+fn unmap_page(mapper: &mut OffsetPageTable, addr: VirtAddr, size: u64) -> Result<(), UnmapError> {
+    match size {
+        Size1GiB::SIZE => unmap_sized::<Size1GiB>(mapper, addr),
+        Size2MiB::SIZE => unmap_sized::<Size2MiB>(mapper, addr),
+        _ => unmap_sized::<Size4KiB>(mapper, addr),
+    }
+}
+
+fn unmap_sized<'a, S: PageSize>(
+    mapper: &mut OffsetPageTable<'a>,
+    addr: VirtAddr,
+) -> Result<(), UnmapError>
+where
+    OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(addr).expect("page not aligned to its size");
+    mapper.unmap(page)?.1.flush();
+    Ok(())
+}
+
+/// Sets the flags of the page of `size` bytes starting at `addr`.
+unsafe fn update_page_flags(
+    mapper: &mut OffsetPageTable,
+    addr: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError> {
+    unsafe {
+        match size {
+            Size1GiB::SIZE => update_sized::<Size1GiB>(mapper, addr, flags),
+            Size2MiB::SIZE => update_sized::<Size2MiB>(mapper, addr, flags),
+            _ => update_sized::<Size4KiB>(mapper, addr, flags),
+        }
+    }
+}
+
+unsafe fn update_sized<'a, S: PageSize>(
+    mapper: &mut OffsetPageTable<'a>,
+    addr: VirtAddr,
+    flags: PageTableFlags,
+) -> Result<(), FlagUpdateError>
+where
+    OffsetPageTable<'a>: Mapper<S>,
+{
+    let page = Page::<S>::from_start_address(addr).expect("page not aligned to its size");
+    unsafe { mapper.update_flags(page, flags)? }.flush();
+    Ok(())
+}
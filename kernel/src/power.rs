@@ -0,0 +1,220 @@
+use crate::acpi::{self, Fadt, KernelAcpiHandler};
+use ::acpi::{AcpiError, Handler, address::GenericAddress, address::MappedGas, sdt::SdtHeader};
+use core::{convert::Infallible, mem, slice};
+use log::{error, info, warn};
+use x86_64::{
+    VirtAddr,
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+};
+
+// PM1 control register bits
+const PM1_SCI_EN: u64 = 1 << 0;
+const PM1_SLP_TYP_SHIFT: u64 = 10;
+const PM1_SLP_TYP_MASK: u64 = 0b111 << PM1_SLP_TYP_SHIFT;
+const PM1_SLP_EN: u64 = 1 << 13;
+
+// AML encodings needed to find the `\_S5` package in the DSDT
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+const KEYBOARD_CONTROLLER_STATUS: u16 = 0x64;
+const KEYBOARD_CONTROLLER_INPUT_FULL: u8 = 1 << 1;
+/// The keyboard controller command that pulses the CPU reset line.
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe;
+
+/// How long the firmware gets to power off or reset the machine before the
+/// next method is tried.
+const TIMEOUT_US: u64 = 100_000;
+
+/// The reason [`acpi_shutdown`] or [`acpi_reset`] didn't work.
+#[derive(Debug)]
+pub enum PowerError {
+    /// The ACPI tables weren't read or have no FADT.
+    NoFadt,
+    /// The system has no PM1 registers to enter a sleep state with.
+    HardwareReduced,
+    /// The DSDT doesn't define the S5 sleep state.
+    NoS5,
+    /// The FADT doesn't describe a reset register.
+    NoResetRegister,
+    /// The firmware didn't hand the ACPI hardware over to the kernel.
+    AcpiModeTimeout,
+    Acpi(AcpiError),
+    /// The registers were written, but the machine is still running.
+    StillRunning,
+}
+
+impl From<AcpiError> for PowerError {
+    fn from(err: AcpiError) -> Self {
+        PowerError::Acpi(err)
+    }
+}
+
+/// Powers off the machine by entering the ACPI S5 sleep state.
+///
+/// Halts with interrupts disabled if that doesn't work, e.g. without ACPI.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    info!("shutting down");
+    let Err(err) = acpi_shutdown();
+    warn!("ACPI shutdown failed: {:?}", err);
+    error!("can't power off, halting");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Resets the machine through the ACPI reset register, falling back to the
+/// keyboard controller and finally a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    info!("rebooting");
+    let Err(err) = acpi_reset();
+    warn!("ACPI reset failed: {:?}", err);
+    if acpi::fadt().is_none_or(|fadt| fadt.has_8042) {
+        keyboard_controller_reset();
+        warn!("keyboard controller reset failed");
+    }
+    warn!("forcing a triple fault");
+    triple_fault();
+}
+
+/// Returns the SLP_TYPa and SLP_TYPb values of the S5 sleep state from the
+/// DSDT, or `None` if there are no ACPI tables or the DSDT doesn't define
+/// `\_S5` as a package.
+pub fn s5_sleep_types() -> Option<(u8, u8)> {
+    let handler = acpi::handler()?;
+    let dsdt = acpi::tables()?.dsdt().ok()?;
+    let length = dsdt.length as usize;
+    let header = mem::size_of::<SdtHeader>();
+    let mapping = unsafe { handler.map_physical_region::<u8>(dsdt.phys_address, length) };
+    let table = unsafe { slice::from_raw_parts(mapping.virtual_start.as_ptr(), length) };
+    parse_s5(table.get(header..)?)
+}
+
+/// Finds `Name(\_S5, Package() { SLP_TYPa, SLP_TYPb, ... })` in an AML
+/// stream without interpreting it.
+fn parse_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    (0..aml.len().saturating_sub(4))
+        .filter(|&i| &aml[i..i + 4] == b"_S5_")
+        .find_map(|i| {
+            let before = &aml[..i];
+            if !before.ends_with(&[AML_NAME_OP]) && !before.ends_with(&[AML_NAME_OP, AML_ROOT_CHAR])
+            {
+                return None;
+            }
+            let mut bytes = aml[i + 4..].iter().copied();
+            if bytes.next()? != AML_PACKAGE_OP {
+                return None;
+            }
+            // the top two bits of the package length give the bytes that follow
+            let lead = bytes.next()?;
+            for _ in 0..lead >> 6 {
+                bytes.next()?;
+            }
+            let _element_count = bytes.next()?;
+            Some((aml_byte(&mut bytes)?, aml_byte(&mut bytes)?))
+        })
+}
+
+/// Reads a small integer constant from an AML stream.
+fn aml_byte(bytes: &mut impl Iterator<Item = u8>) -> Option<u8> {
+    match bytes.next()? {
+        AML_ZERO_OP => Some(0),
+        AML_ONE_OP => Some(1),
+        AML_BYTE_PREFIX => bytes.next(),
+        _ => None,
+    }
+}
+
+/// Enters the ACPI S5 sleep state, which powers off the machine, and only
+/// returns if that didn't work.
+pub fn acpi_shutdown() -> Result<Infallible, PowerError> {
+    let fadt = acpi::fadt().ok_or(PowerError::NoFadt)?;
+    let handler = acpi::handler().ok_or(PowerError::NoFadt)?;
+    if fadt.hardware_reduced {
+        return Err(PowerError::HardwareReduced);
+    }
+    let (slp_typ_a, slp_typ_b) = s5_sleep_types().ok_or(PowerError::NoS5)?;
+
+    enable_acpi_mode(fadt, &handler)?;
+    enter_sleep_state(fadt.pm1a_control, slp_typ_a, &handler)?;
+    if let Some(pm1b_control) = fadt.pm1b_control {
+        enter_sleep_state(pm1b_control, slp_typ_b, &handler)?;
+    }
+    acpi::io_delay(TIMEOUT_US);
+    Err(PowerError::StillRunning)
+}
+
+/// Switches from legacy to ACPI mode, which sleep states can only be entered
+/// in, unless the firmware did so already.
+fn enable_acpi_mode(fadt: &Fadt, handler: &KernelAcpiHandler) -> Result<(), PowerError> {
+    let pm1a_control = unsafe { MappedGas::map_gas(fadt.pm1a_control, handler)? };
+    if pm1a_control.read()? & PM1_SCI_EN != 0 || fadt.smi_command_port == 0 {
+        return Ok(());
+    }
+    unsafe { Port::new(fadt.smi_command_port as u16).write(fadt.acpi_enable) };
+    for _ in 0..TIMEOUT_US / 1000 {
+        if pm1a_control.read()? & PM1_SCI_EN != 0 {
+            return Ok(());
+        }
+        acpi::io_delay(1000);
+    }
+    Err(PowerError::AcpiModeTimeout)
+}
+
+/// Writes the sleep type `slp_typ` to a PM1 control register together with
+/// the sleep enable bit.
+fn enter_sleep_state(
+    register: GenericAddress,
+    slp_typ: u8,
+    handler: &KernelAcpiHandler,
+) -> Result<(), PowerError> {
+    let register = unsafe { MappedGas::map_gas(register, handler)? };
+    let value = register.read()? & !PM1_SLP_TYP_MASK;
+    register.write(value | u64::from(slp_typ) << PM1_SLP_TYP_SHIFT | PM1_SLP_EN)?;
+    Ok(())
+}
+
+/// Writes the reset value to the FADT's reset register, and only returns if
+/// that didn't reset the machine.
+pub fn acpi_reset() -> Result<Infallible, PowerError> {
+    let fadt = acpi::fadt().ok_or(PowerError::NoFadt)?;
+    let handler = acpi::handler().ok_or(PowerError::NoFadt)?;
+    let reset_register = fadt.reset_register.ok_or(PowerError::NoResetRegister)?;
+
+    let register = unsafe { MappedGas::map_gas(reset_register, &handler)? };
+    register.write(u64::from(fadt.reset_value))?;
+    acpi::io_delay(TIMEOUT_US);
+    Err(PowerError::StillRunning)
+}
+
+/// Asks the 8042 keyboard controller to pulse the CPU reset line.
+fn keyboard_controller_reset() {
+    let mut status = Port::<u8>::new(KEYBOARD_CONTROLLER_STATUS);
+    for _ in 0..TIMEOUT_US {
+        if unsafe { status.read() } & KEYBOARD_CONTROLLER_INPUT_FULL == 0 {
+            break;
+        }
+        acpi::io_delay(1);
+    }
+    unsafe { status.write(KEYBOARD_CONTROLLER_RESET) };
+    acpi::io_delay(TIMEOUT_US);
+}
+
+/// Resets the CPU by raising an exception without an IDT, which escalates
+/// to a double and then a triple fault.
+fn triple_fault() -> ! {
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe { lidt(&empty) };
+    interrupts::int3();
+    crate::hlt_loop();
+}
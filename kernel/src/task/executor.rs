@@ -1,4 +1,4 @@
-use super::{Task, TaskId};
+use super::{Task, TaskId, timer};
 use alloc::task::Wake;
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::pin::Pin;
@@ -87,6 +87,7 @@ impl Executor {
 
     pub fn run(&mut self) -> ! {
         loop {
+            timer::wake_expired();
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
@@ -96,7 +97,8 @@ impl Executor {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
 
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        // a timer interrupt wakes up the `hlt` to check the timers
+        if self.task_queue.is_empty() && !timer::has_expired() {
             enable_and_hlt();
         } else {
             interrupts::enable();
@@ -0,0 +1,292 @@
+use crate::time::{self, Instant};
+use alloc::collections::{BTreeMap, BinaryHeap};
+use core::{
+    cmp::Reverse,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use futures_util::stream::Stream;
+use spin::Mutex;
+
+/// The pending timers. Only locked outside of interrupt handlers, the timer
+/// interrupt just compares [`NEXT_DEADLINE`] with the time.
+static TIMERS: Mutex<TimerQueue> = Mutex::new(TimerQueue {
+    deadlines: BinaryHeap::new(),
+    wakers: BTreeMap::new(),
+});
+/// The earliest deadline of the pending timers in nanoseconds, `u64::MAX`
+/// if there is none.
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+/// Set by the timer interrupt once [`NEXT_DEADLINE`] passed.
+static EXPIRED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct TimerId(u64);
+
+impl TimerId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TimerId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+struct TimerQueue {
+    /// The deadlines ordered earliest first. Deadlines of cancelled timers
+    /// have no waker anymore, they stay until they pass or until cancelled
+    /// ones make up more than half of the queue.
+    deadlines: BinaryHeap<Reverse<(Instant, TimerId)>>,
+    wakers: BTreeMap<TimerId, Waker>,
+}
+
+impl TimerQueue {
+    fn update_next_deadline(&self) {
+        let next = self
+            .deadlines
+            .peek()
+            .map_or(u64::MAX, |Reverse((deadline, _))| deadline.as_nanos());
+        NEXT_DEADLINE.store(next, Ordering::SeqCst);
+    }
+
+    /// Drops the deadlines of cancelled timers once they outnumber the
+    /// pending ones, so that long timeouts that are cancelled early don't
+    /// pile up.
+    fn compact(&mut self) {
+        if self.deadlines.len() > 2 * self.wakers.len() {
+            let wakers = &self.wakers;
+            self.deadlines
+                .retain(|Reverse((_, id))| wakers.contains_key(id));
+            self.update_next_deadline();
+        }
+    }
+}
+
+/// Called by the timer interrupt handler.
+///
+/// Must not block or allocate, so it only flags that a timer expired and
+/// leaves waking it to [`wake_expired`].
+pub(crate) fn on_tick() {
+    if time::now().as_nanos() >= NEXT_DEADLINE.load(Ordering::SeqCst) {
+        EXPIRED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Returns whether a timer expired since the last [`wake_expired`].
+pub fn has_expired() -> bool {
+    EXPIRED.load(Ordering::SeqCst)
+}
+
+/// Wakes the tasks whose timers expired, if the timer interrupt noticed
+/// any. Called by the executor before it polls the ready tasks.
+pub fn wake_expired() {
+    if !EXPIRED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let now = time::now();
+    // the lock is released before waking, so wakers may use timers too
+    while let Some(waker) = pop_expired(now) {
+        waker.wake();
+    }
+}
+
+/// Removes the earliest timer if it expired at `now` and returns its waker,
+/// skipping cancelled ones.
+fn pop_expired(now: Instant) -> Option<Waker> {
+    let mut timers = TIMERS.lock();
+    let waker = loop {
+        match timers.deadlines.peek() {
+            Some(&Reverse((deadline, id))) if deadline <= now => {
+                timers.deadlines.pop();
+                if let Some(waker) = timers.wakers.remove(&id) {
+                    break Some(waker);
+                }
+            }
+            _ => break None,
+        }
+    };
+    timers.update_next_deadline();
+    waker
+}
+
+fn register(id: TimerId, deadline: Instant, waker: &Waker) {
+    let mut timers = TIMERS.lock();
+    match timers.wakers.get_mut(&id) {
+        Some(registered) => registered.clone_from(waker),
+        None => {
+            timers.wakers.insert(id, waker.clone());
+            timers.deadlines.push(Reverse((deadline, id)));
+            timers.update_next_deadline();
+        }
+    }
+}
+
+fn cancel(id: TimerId) {
+    let mut timers = TIMERS.lock();
+    timers.wakers.remove(&id);
+    timers.compact();
+}
+
+/// The number of deadlines in the timer queue, including those of cancelled
+/// timers that weren't dropped yet.
+pub fn queued_timers() -> usize {
+    TIMERS.lock().deadlines.len()
+}
+
+/// A future that completes at a deadline, see [`sleep`] and
+/// [`sleep_until`].
+#[derive(Debug)]
+pub struct Sleep {
+    deadline: Instant,
+    id: TimerId,
+    registered: bool,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// Moves the deadline, as if the future was created anew.
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel();
+        self.deadline = deadline;
+        self.id = TimerId::new();
+    }
+
+    fn cancel(&mut self) {
+        if self.registered {
+            cancel(self.id);
+            self.registered = false;
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        if time::now() >= self.deadline {
+            self.cancel();
+            return Poll::Ready(());
+        }
+        register(self.id, self.deadline, cx.waker());
+        self.registered = true;
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+/// Waits until `duration` passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(deadline_after(time::now(), duration))
+}
+
+/// Waits until `deadline`.
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        id: TimerId::new(),
+        registered: false,
+    }
+}
+
+fn deadline_after(instant: Instant, duration: Duration) -> Instant {
+    instant
+        .checked_add(duration)
+        .unwrap_or(Instant::from_nanos(u64::MAX))
+}
+
+/// A stream that yields the time every `period`, see [`interval`].
+///
+/// Ticks that were missed because the task was busy are skipped instead of
+/// being yielded in a burst.
+#[derive(Debug)]
+pub struct Interval {
+    period: Duration,
+    sleep: Sleep,
+}
+
+impl Interval {
+    /// Waits for the next tick and returns its deadline.
+    pub async fn tick(&mut self) -> Instant {
+        core::future::poll_fn(|cx| self.poll_tick(cx)).await
+    }
+
+    pub fn poll_tick(&mut self, cx: &mut Context) -> Poll<Instant> {
+        let deadline = self.sleep.deadline();
+        match Pin::new(&mut self.sleep).poll(cx) {
+            Poll::Ready(()) => {
+                let mut next = deadline_after(deadline, self.period);
+                let now = time::now();
+                if next <= now {
+                    next = deadline_after(now, self.period);
+                }
+                self.sleep.reset(next);
+                Poll::Ready(deadline)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Stream for Interval {
+    type Item = Instant;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Instant>> {
+        self.poll_tick(cx).map(Some)
+    }
+}
+
+/// Yields the time every `period`, with the first tick right away.
+///
+/// Panics if `period` is zero.
+pub fn interval(period: Duration) -> Interval {
+    assert!(!period.is_zero(), "interval period must not be zero");
+    Interval {
+        period,
+        sleep: sleep_until(time::now()),
+    }
+}
+
+/// The error returned by [`timeout`] when the future didn't complete in
+/// time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// A future that completes with the output of another future, or with
+/// [`Elapsed`] at a deadline, see [`timeout`].
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: F,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        // the future is never moved out of the pinned `Timeout`
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        if let Poll::Ready(output) = future.poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        Pin::new(&mut this.sleep).poll(cx).map(|()| Err(Elapsed))
+    }
+}
+
+/// Runs `future` for at most `duration`, completing with [`Elapsed`] if it
+/// didn't complete by then.
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future,
+        sleep: sleep(duration),
+    }
+}
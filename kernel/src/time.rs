@@ -0,0 +1,237 @@
+use crate::interrupts::{InterruptIndex, apic};
+use conquer_once::spin::OnceCell;
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::time::Duration;
+use hpet::Hpet;
+use log::info;
+use x86_64::instructions::interrupts;
+
+pub mod hpet;
+pub mod pit;
+pub mod rtc;
+
+/// The timer interrupt frequency used by the kernel, in Hz.
+pub const DEFAULT_FREQUENCY: u32 = 1000;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+/// How long the timers are measured against the reference clock.
+const CALIBRATION_US: u64 = 10_000;
+
+/// The timers, once [`init`] programmed and calibrated them.
+static CLOCK: OnceCell<Clock> = OnceCell::uninit();
+/// Timer interrupts since [`init`].
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// The timer that raises the timer interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TickSource {
+    Pit,
+    LocalApic,
+}
+
+#[derive(Debug)]
+struct Clock {
+    source: TickSource,
+    /// The actual length of a tick, which differs a bit from the requested
+    /// frequency since the timers count whole ticks of their own clock.
+    tick_nanos: u64,
+    /// TSC ticks per second, or 0 if the TSC couldn't be calibrated.
+    tsc_frequency: u64,
+    /// Whether the TSC runs at a constant rate in all power states, which
+    /// [`now`] relies on.
+    tsc_invariant: bool,
+    /// The TSC when [`init`] was called.
+    tsc_start: u64,
+    hpet: Option<Hpet>,
+}
+
+/// A point in time, measured in nanoseconds since [`init`].
+///
+/// Never goes backwards, unlike the time of day.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Instant(nanos)
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// The time from `earlier` to this instant, or zero if `earlier` is later.
+    pub fn duration_since(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    /// The time that passed since this instant.
+    pub fn elapsed(self) -> Duration {
+        now().duration_since(self)
+    }
+
+    pub fn checked_add(self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// Programs the timer interrupt to `frequency` Hz and calibrates the TSC,
+/// which [`now`] is read from.
+///
+/// The local APIC timer raises the interrupt if [`apic::init`] switched to
+/// the APIC, the PIT otherwise. The HPET is the reference clock for the
+/// calibration if the ACPI tables describe one, the PIT otherwise.
+///
+/// Must be called after [`apic::init`], if that is called at all. Panics if
+/// called more than once.
+pub fn init(frequency: u32) {
+    let frequency = frequency.max(1);
+    interrupts::without_interrupts(|| {
+        let hpet = Hpet::init();
+        let tsc_frequency = calibrate(hpet.as_ref(), rdtsc);
+        let tsc_invariant = tsc_is_invariant();
+
+        let (source, tick_nanos) = match apic::local_apic() {
+            Some(local_apic) => {
+                local_apic.start_timer(InterruptIndex::Timer.as_u8(), u32::MAX, false);
+                let timer_frequency = calibrate(hpet.as_ref(), || {
+                    u64::from(u32::MAX - local_apic.timer_count())
+                });
+                local_apic.stop_timer();
+                let count = (timer_frequency / u64::from(frequency)).clamp(1, u64::from(u32::MAX));
+                apic::start_timer(count as u32);
+                (
+                    TickSource::LocalApic,
+                    count * NANOS_PER_SEC / timer_frequency.max(1),
+                )
+            }
+            None => {
+                let divisor = pit::start_periodic(frequency);
+                let tick_nanos = u64::from(divisor) * NANOS_PER_SEC / pit::BASE_FREQUENCY;
+                (TickSource::Pit, tick_nanos)
+            }
+        };
+
+        TICKS.store(0, Ordering::SeqCst);
+        CLOCK
+            .try_init_once(|| Clock {
+                source,
+                tick_nanos,
+                tsc_frequency,
+                tsc_invariant,
+                tsc_start: rdtsc(),
+                hpet,
+            })
+            .expect("time::init should only be called once");
+        info!(
+            "timer: {:?} ticking every {} ns, TSC at {} MHz{}, calibrated against the {}",
+            source,
+            tick_nanos,
+            tsc_frequency / 1_000_000,
+            match tsc_invariant {
+                true => "",
+                false => " (not invariant, unused)",
+            },
+            match CLOCK.get().is_some_and(|clock| clock.hpet.is_some()) {
+                true => "HPET",
+                false => "PIT",
+            }
+        );
+    });
+}
+
+/// Measures how fast `counter` counts per second against the HPET, or the
+/// PIT without one.
+fn calibrate(hpet: Option<&Hpet>, mut counter: impl FnMut() -> u64) -> u64 {
+    let start = counter();
+    match hpet {
+        Some(hpet) => hpet.wait(CALIBRATION_US),
+        None => pit::wait(CALIBRATION_US),
+    }
+    let end = counter();
+    end.wrapping_sub(start) * 1_000_000 / CALIBRATION_US
+}
+
+/// Whether CPUID reports an invariant TSC, one that keeps its rate across
+/// frequency changes and sleep states.
+fn tsc_is_invariant() -> bool {
+    __cpuid(0x8000_0000).eax >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Counts a timer interrupt, called by the timer interrupt handler.
+pub(crate) fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The number of timer interrupts since [`init`].
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// The timer that raises the timer interrupt, or `None` before [`init`].
+pub fn tick_source() -> Option<TickSource> {
+    CLOCK.get().map(|clock| clock.source)
+}
+
+/// The length of a timer tick, or `None` before [`init`].
+pub fn tick_length() -> Option<Duration> {
+    CLOCK
+        .get()
+        .map(|clock| Duration::from_nanos(clock.tick_nanos))
+}
+
+/// The TSC ticks per second, or `None` before [`init`] or if the TSC
+/// couldn't be calibrated.
+pub fn tsc_frequency() -> Option<u64> {
+    CLOCK
+        .get()
+        .map(|clock| clock.tsc_frequency)
+        .filter(|&frequency| frequency > 0)
+}
+
+/// The current time, read from the TSC if it is invariant and was calibrated
+/// and from the tick counter otherwise. Always zero before [`init`].
+pub fn now() -> Instant {
+    let Some(clock) = CLOCK.get() else {
+        return Instant(0);
+    };
+    match clock.tsc_frequency {
+        _ if !clock.tsc_invariant => Instant(ticks() * clock.tick_nanos),
+        0 => Instant(ticks() * clock.tick_nanos),
+        frequency => {
+            let elapsed = rdtsc().wrapping_sub(clock.tsc_start);
+            Instant(
+                (u128::from(elapsed) * u128::from(NANOS_PER_SEC) / u128::from(frequency)) as u64,
+            )
+        }
+    }
+}
+
+/// The time since the timers were initialized.
+pub fn uptime() -> Duration {
+    now().duration_since(Instant(0))
+}
@@ -0,0 +1,75 @@
+use crate::acpi;
+use crate::memory::vmm;
+use core::ptr;
+use x86_64::VirtAddr;
+
+const FEMTOS_PER_SEC: u64 = 1_000_000_000_000_000;
+
+// registers, as offsets into the MMIO block
+const GENERAL_CAPABILITIES: u64 = 0x00;
+const GENERAL_CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+
+const CONFIGURATION_ENABLE: u64 = 1 << 0;
+
+/// The main counter of the High Precision Event Timer, used as the reference
+/// clock for calibrating the other timers.
+#[derive(Debug)]
+pub struct Hpet {
+    base: VirtAddr,
+    /// The length of a counter tick in femtoseconds.
+    period: u64,
+    /// The bits of the main counter, which may only have 32.
+    counter_mask: u64,
+}
+
+impl Hpet {
+    /// Maps the HPET from the ACPI tables and starts its main counter.
+    ///
+    /// Returns `None` if there is no HPET.
+    pub fn init() -> Option<Self> {
+        let info = acpi::hpet()?;
+        let base = vmm::map_mmio(info.base_address, 0x400).ok()?;
+        let mut hpet = Hpet {
+            base,
+            period: 0,
+            counter_mask: match info.main_counter_64_bit {
+                true => u64::MAX,
+                false => u64::from(u32::MAX),
+            },
+        };
+        hpet.period = hpet.read(GENERAL_CAPABILITIES) >> 32;
+        if hpet.period == 0 {
+            return None;
+        }
+        let configuration = hpet.read(GENERAL_CONFIGURATION);
+        hpet.write(GENERAL_CONFIGURATION, configuration | CONFIGURATION_ENABLE);
+        Some(hpet)
+    }
+
+    fn read(&self, register: u64) -> u64 {
+        unsafe { ptr::read_volatile((self.base + register).as_ptr()) }
+    }
+
+    fn write(&self, register: u64, value: u64) {
+        unsafe { ptr::write_volatile((self.base + register).as_mut_ptr(), value) }
+    }
+
+    /// The ticks of the main counter per second.
+    pub fn frequency(&self) -> u64 {
+        FEMTOS_PER_SEC / self.period
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.read(MAIN_COUNTER)
+    }
+
+    /// Busy waits for `microseconds`.
+    pub fn wait(&self, microseconds: u64) {
+        let ticks = microseconds * self.frequency() / 1_000_000;
+        let start = self.counter();
+        while self.counter().wrapping_sub(start) & self.counter_mask < ticks {
+            core::hint::spin_loop();
+        }
+    }
+}
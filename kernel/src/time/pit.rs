@@ -0,0 +1,54 @@
+use x86_64::instructions::port::Port;
+
+/// The frequency the PIT's counters are decremented at.
+pub const BASE_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL_0: u16 = 0x40;
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// The keyboard controller port B, which gates channel 2 and reads its output.
+const PORT_B: u16 = 0x61;
+
+// command bits: channel, access lobyte/hibyte and operating mode
+const COMMAND_CHANNEL_0: u8 = 0b00 << 6;
+const COMMAND_CHANNEL_2: u8 = 0b10 << 6;
+const COMMAND_LOBYTE_HIBYTE: u8 = 0b11 << 4;
+const MODE_INTERRUPT_ON_TERMINAL_COUNT: u8 = 0b000 << 1;
+const MODE_RATE_GENERATOR: u8 = 0b010 << 1;
+
+const PORT_B_CHANNEL_2_GATE: u8 = 1 << 0;
+const PORT_B_SPEAKER: u8 = 1 << 1;
+const PORT_B_CHANNEL_2_OUTPUT: u8 = 1 << 5;
+
+/// Makes channel 0 raise IRQ0 at about `frequency` Hz and returns the
+/// divisor it counts down from, which gives the exact period.
+pub fn start_periodic(frequency: u32) -> u16 {
+    let divisor = (BASE_FREQUENCY + u64::from(frequency) / 2) / u64::from(frequency.max(1));
+    let divisor = divisor.clamp(1, u64::from(u16::MAX)) as u16;
+    unsafe {
+        Port::new(COMMAND).write(COMMAND_CHANNEL_0 | COMMAND_LOBYTE_HIBYTE | MODE_RATE_GENERATOR);
+        let mut channel = Port::<u8>::new(CHANNEL_0);
+        channel.write(divisor as u8);
+        channel.write((divisor >> 8) as u8);
+    }
+    divisor
+}
+
+/// Busy waits for `microseconds` with channel 2, which doesn't raise an
+/// interrupt. At most about 54 ms can be waited at once.
+pub fn wait(microseconds: u64) {
+    let count = (BASE_FREQUENCY * microseconds / 1_000_000).clamp(1, u64::from(u16::MAX));
+    let mut port_b = Port::<u8>::new(PORT_B);
+    unsafe {
+        let gate = port_b.read() & !PORT_B_SPEAKER | PORT_B_CHANNEL_2_GATE;
+        port_b.write(gate);
+        Port::new(COMMAND)
+            .write(COMMAND_CHANNEL_2 | COMMAND_LOBYTE_HIBYTE | MODE_INTERRUPT_ON_TERMINAL_COUNT);
+        let mut channel = Port::<u8>::new(CHANNEL_2);
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+        while port_b.read() & PORT_B_CHANNEL_2_OUTPUT == 0 {
+            core::hint::spin_loop();
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use super::Instant;
+use crate::acpi;
+use crate::interrupts::{IrqError, register_irq, unregister_irq};
+use conquer_once::spin::OnceCell;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The ISA IRQ of the RTC.
+const IRQ: u8 = 8;
+
+// CMOS registers of the RTC
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const STATUS_D: u8 = 0x0d;
+
+/// Set in the CMOS index to keep NMIs disabled while accessing it.
+const NMI_DISABLE: u8 = 1 << 7;
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_A_RATE: u8 = 0x0f;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+/// Days from 0000-03-01 to 1970-01-01 in the proleptic Gregorian calendar.
+const UNIX_EPOCH_DAYS: i64 = 719_468;
+const DAYS_PER_ERA: i64 = 146_097;
+
+static CMOS: Mutex<Cmos> = Mutex::new(Cmos {
+    index: Port::new(0x70),
+    data: Port::new(0x71),
+});
+/// The UNIX timestamp read by [`init`] and the monotonic time it was read at.
+static BOOT_TIME: OnceCell<(u64, Instant)> = OnceCell::uninit();
+/// Periodic RTC interrupts so far.
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// A date and time in UTC, or whatever time zone the RTC is set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1 to 12.
+    pub month: u8,
+    /// 1 to 31.
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// The seconds since 1970-01-01 00:00:00, or 0 for earlier times.
+    pub fn unix_timestamp(&self) -> u64 {
+        // days since the epoch with years starting in March, which puts the
+        // leap day at the end of the year
+        let year = i64::from(self.year) - i64::from(self.month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let month = (i64::from(self.month) + 9) % 12;
+        let day_of_year = (153 * month + 2) / 5 + i64::from(self.day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * DAYS_PER_ERA + day_of_era - UNIX_EPOCH_DAYS;
+
+        let seconds = u64::from(self.hour) * 3600 + u64::from(self.minute) * 60;
+        u64::try_from(days).map_or(0, |days| {
+            days * SECONDS_PER_DAY + seconds + u64::from(self.second)
+        })
+    }
+
+    /// The date and time `timestamp` seconds after 1970-01-01 00:00:00.
+    pub fn from_unix_timestamp(timestamp: u64) -> Self {
+        let days = (timestamp / SECONDS_PER_DAY) as i64 + UNIX_EPOCH_DAYS;
+        let seconds = timestamp % SECONDS_PER_DAY;
+        let era = days.div_euclid(DAYS_PER_ERA);
+        let day_of_era = days - era * DAYS_PER_ERA;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month + 2) / 5 + 1;
+        let month = if month < 10 { month + 3 } else { month - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    /// Formats as `2024-02-29 13:37:00`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+struct Cmos {
+    index: Port<u8>,
+    data: Port<u8>,
+}
+
+impl Cmos {
+    fn read(&mut self, register: u8) -> u8 {
+        let value = unsafe {
+            self.index.write(NMI_DISABLE | register);
+            self.data.read()
+        };
+        self.enable_nmi();
+        value
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        unsafe {
+            self.index.write(NMI_DISABLE | register);
+            self.data.write(value);
+        }
+        self.enable_nmi();
+    }
+
+    /// Clears the NMI disable bit again after an access. The index port
+    /// can't be read back, but nothing else masks NMIs, so they were enabled
+    /// before.
+    fn enable_nmi(&mut self) {
+        // STATUS_D is read only, selecting it can't lead to a stray write
+        unsafe { self.index.write(STATUS_D) };
+    }
+
+    /// Reads the time registers as the RTC encodes them, and the century
+    /// register if there is one, once no update is in progress.
+    fn read_registers(&mut self, century: Option<u8>) -> [u8; 7] {
+        while self.read(STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+            core::hint::spin_loop();
+        }
+        let [second, minute, hour, day, month, year] =
+            [SECONDS, MINUTES, HOURS, DAY, MONTH, YEAR].map(|register| self.read(register));
+        let century = century.map_or(0, |century| self.read(century));
+        [second, minute, hour, day, month, year, century]
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Reads the date and time from the RTC.
+///
+/// The registers are read until two reads in a row agree, so that an update
+/// of the RTC in between can't tear them. Without a century register in the
+/// FADT, the year is assumed to be in the 21st century.
+pub fn read() -> DateTime {
+    let century_register = acpi::fadt()
+        .map(|fadt| fadt.century_register)
+        .filter(|&register| register != 0);
+
+    let (registers, status_b) = interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let mut registers = cmos.read_registers(century_register);
+        loop {
+            let again = cmos.read_registers(century_register);
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        (registers, cmos.read(STATUS_B))
+    });
+
+    let [second, minute, hour, day, month, year, century] = registers;
+    let pm = hour & HOUR_PM != 0;
+    let decode = |value: u8| match status_b & STATUS_B_BINARY {
+        0 => from_bcd(value),
+        _ => value,
+    };
+    let mut hour = decode(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight, 12 PM noon
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+    let century = match century_register {
+        Some(_) => u16::from(decode(century)),
+        None => 20,
+    };
+
+    DateTime {
+        year: century * 100 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+/// Reads the RTC once, after which [`now`] and [`unix_timestamp`] follow
+/// the monotonic clock instead of reading the RTC again.
+///
+/// Must be called after [`super::init`]. Panics if called more than once.
+pub fn init() -> DateTime {
+    let date_time = read();
+    BOOT_TIME
+        .try_init_once(|| (date_time.unix_timestamp(), super::now()))
+        .expect("rtc::init should only be called once");
+    date_time
+}
+
+/// The seconds since 1970-01-01 00:00:00, or `None` before [`init`].
+pub fn unix_timestamp() -> Option<u64> {
+    let (timestamp, instant) = BOOT_TIME.get()?;
+    Some(timestamp + instant.elapsed().as_secs())
+}
+
+/// The current date and time, or `None` before [`init`].
+pub fn now() -> Option<DateTime> {
+    unix_timestamp().map(DateTime::from_unix_timestamp)
+}
+
+/// Makes the RTC raise IRQ8 at `32768 >> (rate - 1)` Hz, from 2 Hz for rate
+/// 15 to 8192 Hz for rate 3, and registers the handler that counts them.
+///
+/// Panics if `rate` is not within 3 to 15.
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), IrqError> {
+    assert!((3..=15).contains(&rate), "invalid RTC rate {}", rate);
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_a = cmos.read(STATUS_A);
+        cmos.write(STATUS_A, status_a & !STATUS_A_RATE | rate);
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        // the RTC raises no further interrupts until status C is read
+        cmos.read(STATUS_C);
+    });
+    match register_irq(IRQ, "rtc", handle_interrupt) {
+        Ok(()) | Err(IrqError::AlreadyRegistered) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Stops the periodic interrupt.
+pub fn disable_periodic_interrupt() {
+    interrupts::without_interrupts(|| {
+        let mut cmos = CMOS.lock();
+        let status_b = cmos.read(STATUS_B);
+        cmos.write(STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+    });
+    // not registered if the interrupt was never enabled
+    unregister_irq(IRQ, "rtc").ok();
+}
+
+/// The number of periodic RTC interrupts so far.
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+fn handle_interrupt(_irq: u8) -> bool {
+    // reading status C acknowledges the interrupt, the lock is never held
+    // with interrupts enabled
+    let status_c = CMOS.lock().read(STATUS_C);
+    let periodic = status_c & STATUS_C_PERIODIC_INTERRUPT != 0;
+    if periodic {
+        PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    }
+    periodic
+}
+
+#[test_case]
+fn unix_timestamp_round_trips() {
+    let leap_day = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 0,
+    };
+    assert_eq!(leap_day.unix_timestamp(), 1_709_213_820);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_213_820), leap_day);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
@@ -0,0 +1,123 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::memory::address_space::{self, AddressSpace};
+use kernel::memory::{self, vmm::VirtRange};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PageTableFlags;
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::BitmapFrameAllocator;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+const USER_PAGE: u64 = 0x40_0000;
+
+fn user_range() -> VirtRange {
+    VirtRange::new(VirtAddr::new(USER_PAGE), 4096)
+}
+
+fn flags() -> PageTableFlags {
+    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
+}
+
+#[test_case]
+fn user_half_is_private() {
+    let mut space = AddressSpace::new().unwrap();
+    space.map(user_range(), flags()).unwrap();
+    assert!(space.translate(VirtAddr::new(USER_PAGE)).is_some());
+    assert!(memory::vmm::translate(VirtAddr::new(USER_PAGE)).is_none());
+
+    let ptr = USER_PAGE as *mut u64;
+    unsafe {
+        space.switch_to();
+        // the kernel half, including the stack and the heap, is still there
+        assert!(space.is_active());
+        ptr.write_volatile(7);
+        assert_eq!(ptr.read_volatile(), 7);
+        address_space::switch_to_kernel();
+    }
+    assert!(!space.is_active());
+}
+
+#[test_case]
+fn mapped_pages_are_zeroed() {
+    let mut space = AddressSpace::new().unwrap();
+    let ptr = USER_PAGE as *mut u64;
+    for _ in 0..2 {
+        // the second time, the frame freed by unmap is likely handed out again
+        space.map(user_range(), flags()).unwrap();
+        unsafe {
+            space.switch_to();
+            assert_eq!(ptr.read_volatile(), 0);
+            ptr.write_volatile(0xdead_beef);
+            address_space::switch_to_kernel();
+        }
+        space.unmap(user_range());
+    }
+}
+
+#[test_case]
+fn drop_frees_all_frames() {
+    // the first address space allocates the shared kernel-half tables
+    drop(AddressSpace::new().unwrap());
+    let free_before = memory::frame_allocator().free_frames();
+
+    let mut space = AddressSpace::new().unwrap();
+    space
+        .map(VirtRange::new(VirtAddr::new(USER_PAGE), 16 * 4096), flags())
+        .unwrap();
+    drop(space);
+    assert_eq!(memory::frame_allocator().free_frames(), free_before);
+}
+
+#[test_case]
+fn clone_copies_on_write() {
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(user_range(), flags()).unwrap();
+    let ptr = USER_PAGE as *mut u64;
+    unsafe {
+        parent.switch_to();
+        ptr.write_volatile(1);
+    }
+
+    let mut child = parent.try_clone().unwrap();
+    let addr = VirtAddr::new(USER_PAGE);
+    assert_eq!(parent.translate(addr), child.translate(addr));
+
+    unsafe {
+        ptr.write_volatile(2);
+        child.switch_to();
+        assert_eq!(ptr.read_volatile(), 1);
+        ptr.write_volatile(3);
+        parent.switch_to();
+        assert_eq!(ptr.read_volatile(), 2);
+        address_space::switch_to_kernel();
+    }
+    assert_ne!(parent.translate(addr), child.translate(addr));
+}
@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::interrupts::apic;
+use x86_64::VirtAddr;
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    kernel::acpi::init(boot_info.rsdp_addr.into_option().expect("no RSDP"))
+        .expect("ACPI tables not found");
+    apic::init().expect("APIC initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn acpi_tables_are_read() {
+    let madt = kernel::acpi::madt().expect("no MADT");
+    assert!(!madt.io_apics.is_empty());
+    assert_eq!(madt.processors.first(), Some(&0));
+    let fadt = kernel::acpi::fadt().expect("no FADT");
+    assert!(fadt.pm_timer.is_some());
+}
+
+#[test_case]
+fn local_apic_is_enabled() {
+    assert!(apic::is_enabled());
+    assert_eq!(apic::local_apic().unwrap().id(), 0);
+}
+
+#[test_case]
+fn timer_counts_down() {
+    let local_apic = apic::local_apic().unwrap();
+    let first = local_apic.timer_count();
+    for _ in 0..10_000 {
+        core::hint::spin_loop();
+    }
+    assert_ne!(local_apic.timer_count(), first);
+}
+
+#[test_case]
+fn timer_tick_interrupts_the_cpu() {
+    use kernel::interrupts::{irq::TIMER_IRQ, irq_count};
+
+    // the PIT stays masked, so only the local APIC timer raises the timer IRQ
+    let ticks = irq_count(TIMER_IRQ);
+    for _ in 0..3 {
+        if irq_count(TIMER_IRQ) > ticks {
+            break;
+        }
+        x86_64::instructions::hlt();
+    }
+    assert!(irq_count(TIMER_IRQ) > ticks);
+}
+
+#[test_case]
+fn timer_irq_can_be_masked() {
+    use kernel::interrupts::{IrqError, irq::TIMER_IRQ, irq_count, mask_irq, unmask_irq};
+
+    let local_apic = apic::local_apic().unwrap();
+    mask_irq(TIMER_IRQ).unwrap();
+    let ticks = irq_count(TIMER_IRQ);
+    // the count is reloaded when the timer fires
+    let mut last = local_apic.timer_count();
+    loop {
+        let count = local_apic.timer_count();
+        if count > last {
+            break;
+        }
+        last = count;
+    }
+    assert_eq!(irq_count(TIMER_IRQ), ticks);
+    unmask_irq(TIMER_IRQ).unwrap();
+    x86_64::instructions::hlt();
+    assert!(irq_count(TIMER_IRQ) > ticks);
+
+    assert_eq!(mask_irq(16), Err(IrqError::InvalidIrq));
+    assert_eq!(unmask_irq(16), Err(IrqError::InvalidIrq));
+}
@@ -0,0 +1,81 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::backtrace::{self, Backtrace};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    backtrace::init(boot_info);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn symbolizes_kernel_functions() {
+    let address = kernel::hlt_loop as fn() -> ! as usize as u64;
+    let location = backtrace::symbolize(address + 1).expect("hlt_loop not found");
+    assert_eq!(location.offset, 1);
+    assert_eq!(location.to_string(), "kernel::hlt_loop+0x1");
+    assert!(backtrace::symbolize(0x1000).is_none());
+}
+
+#[inline(never)]
+fn nested() -> alloc::string::String {
+    Backtrace::capture().to_string()
+}
+
+#[test_case]
+fn backtrace_lists_the_callers() {
+    let backtrace = nested();
+    let mut lines = backtrace.lines();
+    assert_eq!(lines.next(), Some("backtrace:"));
+    let first = lines.next().expect("no frames");
+    assert!(
+        first.contains("backtrace::backtrace_lists_the_callers+"),
+        "{}",
+        first
+    );
+    assert!(lines.count() > 1);
+}
+
+#[test_case]
+fn walk_follows_one_stack_switch() {
+    // three frames of a frame pointer and a return address each, where the
+    // first links down to the second like an exception handler on its own
+    // stack, and the third links down again
+    let mut stack = [0u64; 6];
+    let base = stack.as_mut_ptr() as u64;
+    stack[4..6].copy_from_slice(&[base, 1]);
+    stack[0..2].copy_from_slice(&[base + 16, 2]);
+    stack[2..4].copy_from_slice(&[base, 3]);
+
+    let frames: Vec<u64> = backtrace::frames_from(base + 32).collect();
+    assert_eq!(frames, [1, 2, 3]);
+}
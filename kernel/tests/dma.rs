@@ -0,0 +1,77 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::memory::{
+    self,
+    dma::{DmaBuffer, DmaOptions},
+    vmm,
+};
+use x86_64::VirtAddr;
+use x86_64::structures::paging::PhysFrame;
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::BitmapFrameAllocator;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn buffer_is_contiguous_and_zeroed() {
+    let mut buffer = DmaBuffer::new(8, DmaOptions::default()).unwrap();
+    assert_eq!(buffer.size(), 8 * 4096);
+    assert!(buffer.phys_addr().is_aligned(4096u64));
+    for page in 0..8u64 {
+        let offset = page * 4096 + 8;
+        assert_eq!(
+            vmm::translate(buffer.virt_addr() + offset),
+            Some(buffer.phys_addr() + offset)
+        );
+    }
+    assert!(buffer.as_slice().iter().all(|&byte| byte == 0));
+    buffer.as_mut_slice()[100] = 0xab;
+    assert_eq!(buffer.as_slice()[100], 0xab);
+}
+
+#[test_case]
+fn buffer_below_4gib_uncached() {
+    let options = DmaOptions {
+        below_4gib: true,
+        uncached: true,
+    };
+    let buffer = DmaBuffer::new(4, options).unwrap();
+    assert!(buffer.phys_addr().as_u64() + buffer.size() <= 0x1_0000_0000);
+}
+
+#[test_case]
+fn drop_frees_frames() {
+    let free_before = memory::frame_allocator().free_frames();
+    let buffer = DmaBuffer::new(600, DmaOptions::default()).unwrap();
+    // large buffers are aligned for huge pages
+    assert!(buffer.phys_addr().is_aligned(2u64 * 1024 * 1024));
+    let phys = buffer.phys_addr();
+    drop(buffer);
+    assert_eq!(memory::frame_allocator().free_frames(), free_before);
+    assert!(!memory::frame_allocator().is_allocated(PhysFrame::containing_address(phys)));
+}
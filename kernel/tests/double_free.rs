@@ -0,0 +1,56 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::alloc::{Layout, alloc, dealloc};
+use alloc::string::ToString;
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("double_free::double_free...\t");
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    double_free();
+    serial_println!("[double free not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn double_free() {
+    let layout = Layout::new::<[u64; 4]>();
+    unsafe {
+        let ptr = alloc(layout);
+        dealloc(ptr, layout);
+        dealloc(ptr, layout);
+    }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = info.message().to_string();
+    if message.starts_with("heap corruption at ") && message.ends_with(": double free") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,52 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("exception::invalid_opcode_panics_with_report...\t");
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    // the handler returns after breakpoints
+    x86_64::instructions::interrupts::int3();
+    unsafe { core::arch::asm!("mov rax, 0x1badc0de", "ud2", out("rax") _) };
+
+    serial_println!("[execution continued after ud2]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = info.message().to_string();
+    if message.starts_with("EXCEPTION: invalid opcode (vector 6)")
+        && message.contains("rax 0x000000001badc0de")
+        && message.contains("cr3 ")
+    {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{BootInfo, entry_point};
+use conquer_once::spin::OnceCell;
+use core::panic::PanicInfo;
+use kernel::memory::{BitmapFrameAllocator, report};
+use spin::Mutex;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PhysFrame, Size1GiB, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+static FRAME_ALLOCATOR: OnceCell<Mutex<BitmapFrameAllocator>> = OnceCell::uninit();
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    FRAME_ALLOCATOR.init_once(|| Mutex::new(frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn allocate_distinct_frames() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let a: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    let b: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_ne!(a, b);
+    unsafe {
+        allocator.deallocate_frame(a);
+        allocator.deallocate_frame(b);
+    }
+}
+
+#[test_case]
+fn deallocated_frames_are_reused() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let free = allocator.free_frames();
+    let frame: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(allocator.free_frames(), free - 1);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free);
+    let again: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+    assert_eq!(frame, again);
+    unsafe { allocator.deallocate_frame(again) };
+}
+
+#[test_case]
+fn contiguous_allocation() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let range = allocator.allocate_contiguous(16, 4).unwrap();
+    assert_eq!(range.count(), 16);
+    assert_eq!(range.start.start_address().as_u64() % (4 * 4096), 0);
+    for frame in range {
+        assert!(allocator.is_allocated(frame));
+    }
+    unsafe { allocator.deallocate_contiguous(range) };
+    for frame in range {
+        assert!(!allocator.is_allocated(frame));
+    }
+}
+
+#[test_case]
+fn huge_frame_allocation() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let free = allocator.free_frames();
+    let frame: PhysFrame<Size2MiB> = allocator.allocate_frame().unwrap();
+    assert!(frame.start_address().is_aligned(2u64 * 1024 * 1024));
+    assert_eq!(allocator.free_frames(), free - 512);
+    unsafe { allocator.deallocate_frame(frame) };
+    assert_eq!(allocator.free_frames(), free);
+
+    // QEMU's default 128 MiB of RAM can't hold a 1 GiB frame
+    let giant: Option<PhysFrame<Size1GiB>> = allocator.allocate_frame();
+    if let Some(frame) = giant {
+        unsafe { allocator.deallocate_frame(frame) };
+    }
+    assert_eq!(allocator.free_frames(), free);
+}
+
+#[test_case]
+fn contiguous_allocation_below_limit() {
+    let mut allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let limit = PhysAddr::new(16 * 1024 * 1024);
+    let range = allocator
+        .allocate_contiguous_below(8, 1, Some(limit))
+        .unwrap();
+    assert!(range.end.start_address() <= limit);
+    unsafe { allocator.deallocate_contiguous(range) };
+    assert!(
+        allocator
+            .allocate_contiguous_below(8, 1, Some(PhysAddr::new(0)))
+            .is_none()
+    );
+}
+
+#[test_case]
+fn memory_map_totals() {
+    let allocator = FRAME_ALLOCATOR.get().unwrap().lock();
+    let stats = report::memory_map_stats(allocator.memory_map());
+    assert!(stats.regions > 0);
+    // the frame allocator manages exactly the usable memory
+    assert!(stats.usable >= allocator.total_frames() as u64 * 4096);
+    assert!(stats.usable - allocator.total_frames() as u64 * 4096 < stats.regions as u64 * 4096);
+}
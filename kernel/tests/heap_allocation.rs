@@ -8,22 +8,27 @@ extern crate alloc;
 
 use alloc::boxed::Box;
 use alloc::vec::Vec;
-use bootloader::{BootInfo, entry_point};
+use bootloader_api::{BootInfo, entry_point};
+use core::alloc::Layout;
 use core::panic::PanicInfo;
-use kernel::allocator::HEAP_SIZE;
+use kernel::allocator::linked_list::LinkedListAllocator;
+use kernel::allocator::slab::SlabCache;
+use kernel::allocator::{self, HEAP_MAX_SIZE, HEAP_SIZE, Locked, oom};
+use spin::Mutex;
 
-entry_point!(main);
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
 
-fn main(boot_info: &'static BootInfo) -> ! {
-    use kernel::allocator;
-    use kernel::memory::{self, BootInfoFrameAllocator};
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::memory::{self, BitmapFrameAllocator};
     use x86_64::VirtAddr;
 
     kernel::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
 
     test_main();
     loop {}
@@ -69,3 +74,142 @@ fn many_boxes_long_lived() {
     }
     assert_eq!(*long_lived, 1); // new
 }
+
+#[test_case]
+fn heap_grows_on_demand() {
+    let initial_size = allocator::heap_size();
+    let big = Vec::<u8>::with_capacity(4 * HEAP_SIZE);
+    assert!(big.capacity() >= 4 * HEAP_SIZE);
+    assert!(allocator::heap_size() > initial_size);
+}
+
+#[test_case]
+fn mixed_sizes() {
+    let mut boxes = Vec::new();
+    for i in 0..200 {
+        let size = 1 << (i % 13);
+        boxes.push(alloc::vec![i as u8; size]);
+    }
+    for (i, b) in boxes.iter().enumerate() {
+        assert!(b.iter().all(|&x| x == i as u8));
+    }
+}
+
+#[test_case]
+fn large_layout_fallback() {
+    for _ in 0..100 {
+        let big = Box::new([7u64; 1024]);
+        assert_eq!(big.iter().sum::<u64>(), 7 * 1024);
+    }
+}
+
+#[test_case]
+fn slab_cache_reuses_slots() {
+    static CACHE: Locked<SlabCache<[u64; 4]>> = Locked::new(SlabCache::new());
+
+    for i in 0..1000 {
+        let object = CACHE.alloc([i; 4]);
+        assert_eq!(object[3], i);
+    }
+    assert_eq!(CACHE.lock().slabs(), 1);
+    assert_eq!(CACHE.lock().in_use(), 0);
+
+    let objects: Vec<_> = (0..200).map(|i| CACHE.alloc([i; 4])).collect();
+    assert_eq!(CACHE.lock().in_use(), 200);
+    assert!(objects.iter().enumerate().all(|(i, o)| o[0] == i as u64));
+}
+
+#[test_case]
+fn stats_track_allocations() {
+    let before = allocator::heap_stats();
+    let value = Box::new([0u8; 100]);
+    let during = allocator::heap_stats();
+    assert_eq!(during.allocated_bytes, before.allocated_bytes + 100);
+    assert_eq!(during.allocations, before.allocations + 1);
+    assert!(during.high_water_mark >= during.allocated_bytes);
+    drop(value);
+    let after = allocator::heap_stats();
+    assert_eq!(after.allocated_bytes, before.allocated_bytes);
+    assert_eq!(after.deallocations, before.deallocations + 1);
+    assert!(after.free_regions > 0);
+    assert!(after.largest_free_block <= after.free_bytes);
+}
+
+#[test_case]
+fn no_net_allocations() {
+    allocator::assert_no_net_allocations(|| {
+        let mut vec = Vec::new();
+        for i in 0..100 {
+            vec.push(Box::new(i));
+        }
+        assert_eq!(*vec[99], 99);
+    });
+}
+
+#[test_case]
+fn full_heap_allocation_after_churn() {
+    // a separate allocator on its own memory, so that nothing else allocates
+    // from it in between
+    const SIZE: usize = 64 * 1024;
+    let mut memory = alloc::vec![0u64; SIZE / 8];
+    let mut heap = LinkedListAllocator::new();
+    unsafe { heap.init(memory.as_mut_ptr() as usize, SIZE) };
+
+    let mut live: Vec<(*mut u8, Layout)> = Vec::with_capacity(64);
+    for round in 0..50 {
+        for i in 0..16 {
+            let layout = Layout::from_size_align(64 + (i * 97 + round) % 1000, 8).unwrap();
+            let ptr = heap.allocate(layout);
+            assert!(!ptr.is_null());
+            live.push((ptr, layout));
+        }
+        // free every other allocation to leave holes behind
+        let mut keep = false;
+        live.retain(|&(ptr, layout)| {
+            keep = !keep;
+            if !keep {
+                unsafe { heap.deallocate(ptr, layout) };
+            }
+            keep
+        });
+    }
+    for (ptr, layout) in live.drain(..) {
+        unsafe { heap.deallocate(ptr, layout) };
+    }
+
+    // all freed regions must have merged back into one
+    let stats = heap.stats();
+    assert_eq!(stats.free_regions, 1);
+    assert_eq!(stats.largest_free_block, SIZE);
+    assert_eq!(stats.free_bytes, SIZE);
+    let full = Layout::from_size_align(SIZE, 8).unwrap();
+    assert!(!heap.allocate(full).is_null());
+}
+
+#[test_case]
+fn fallible_allocation_fails_gracefully() {
+    assert!(allocator::try_vec(0u8, 2 * HEAP_MAX_SIZE).is_err());
+    let small = allocator::try_vec(7u8, 100).unwrap();
+    assert!(small.iter().all(|&x| x == 7));
+    assert_eq!(*allocator::try_box(42).unwrap(), 42);
+}
+
+#[test_case]
+fn reclaim_frees_memory_before_failing() {
+    static CACHE: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+    fn drop_cache(_needed: usize) -> usize {
+        CACHE.lock().take().map_or(0, |cache| cache.capacity())
+    }
+
+    let size = 8 * HEAP_SIZE;
+    *CACHE.lock() = Some(Vec::with_capacity(size));
+    allocator::set_heap_limit(allocator::heap_size());
+    assert!(allocator::heap_stats().largest_free_block < size);
+
+    oom::register_reclaimer("test cache", drop_cache);
+    let buffer = allocator::try_vec(0u8, size);
+    oom::unregister_reclaimer("test cache");
+    allocator::set_heap_limit(HEAP_MAX_SIZE);
+    assert!(CACHE.lock().is_none());
+    assert_eq!(buffer.unwrap().len(), size);
+}
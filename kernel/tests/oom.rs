@@ -0,0 +1,53 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::allocator;
+use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("oom::out_of_memory...\t");
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    out_of_memory();
+    serial_println!("[allocation did not fail]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+fn out_of_memory() {
+    allocator::set_heap_limit(allocator::heap_size());
+    let vec = Vec::<u8>::with_capacity(2 * allocator::HEAP_SIZE);
+    core::hint::black_box(vec);
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = info.message().to_string();
+    if message.starts_with("out of memory: allocation of ") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,48 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::power;
+use x86_64::VirtAddr;
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    kernel::acpi::init(boot_info.rsdp_addr.into_option().expect("no RSDP"))
+        .expect("ACPI tables not found");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn dsdt_defines_s5() {
+    let (slp_typ_a, slp_typ_b) = power::s5_sleep_types().expect("no \\_S5 package in the DSDT");
+    assert!(slp_typ_a < 8 && slp_typ_b < 8);
+}
+
+#[test_case]
+fn fadt_has_pm1_control() {
+    let fadt = kernel::acpi::fadt().expect("no FADT");
+    assert!(!fadt.hardware_reduced);
+    assert_ne!(fadt.pm1a_control.address, 0);
+}
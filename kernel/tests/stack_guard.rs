@@ -0,0 +1,62 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::ToString;
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::memory::stack::{DEFAULT_STACK_SIZE, KernelStack};
+use kernel::{QemuExitCode, exit_qemu, serial_print, serial_println};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+    use x86_64::VirtAddr;
+
+    serial_print!("stack_guard::stack_guard...\t");
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    let stack = Box::leak(Box::new(
+        KernelStack::new("overflow-test", DEFAULT_STACK_SIZE).expect("stack allocation failed"),
+    ));
+    unsafe { stack.switch_to(overflow_task) }
+}
+
+extern "C" fn overflow_task() -> ! {
+    stack_overflow();
+    serial_println!("[stack overflow not detected]");
+    exit_qemu(QemuExitCode::Failed);
+    loop {}
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+
+    volatile::Volatile::new(0).read();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    let message = info.message().to_string();
+    if message.starts_with("stack overflow in overflow-test") {
+        serial_println!("[ok]");
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        serial_println!("[failed]\n");
+        serial_println!("Error: {}\n", info);
+        exit_qemu(QemuExitCode::Failed);
+    }
+    loop {}
+}
@@ -0,0 +1,208 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader_api::{BootInfo, entry_point};
+extern crate alloc;
+
+use alloc::{sync::Arc, task::Wake};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::{Pin, pin};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use core::time::Duration;
+use kernel::interrupts::apic;
+use kernel::task::timer;
+use kernel::time::{self, rtc};
+use x86_64::VirtAddr;
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::{self, BitmapFrameAllocator};
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+    kernel::acpi::init(boot_info.rsdp_addr.into_option().expect("no RSDP"))
+        .expect("ACPI tables not found");
+    apic::init().expect("APIC initialization failed");
+    time::init(time::DEFAULT_FREQUENCY);
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn local_apic_drives_the_tick() {
+    assert_eq!(time::tick_source(), Some(time::TickSource::LocalApic));
+    let tick = time::tick_length().unwrap();
+    assert!(tick > Duration::from_micros(900) && tick < Duration::from_micros(1100));
+}
+
+#[test_case]
+fn tsc_is_calibrated() {
+    let frequency = time::tsc_frequency().expect("TSC not calibrated");
+    assert!(frequency > 100_000_000, "TSC at {} Hz", frequency);
+}
+
+#[test_case]
+fn now_is_monotonic() {
+    let mut last = time::now();
+    for _ in 0..1000 {
+        let now = time::now();
+        assert!(now >= last);
+        last = now;
+    }
+}
+
+#[test_case]
+fn uptime_follows_the_ticks() {
+    let start = time::now();
+    let ticks = time::ticks();
+    while time::ticks() < ticks + 10 {
+        x86_64::instructions::hlt();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(8), "{:?}", elapsed);
+    assert!(time::uptime() >= elapsed);
+}
+
+#[test_case]
+fn sleep_waits_for_its_duration() {
+    let start = time::now();
+    run(async {
+        timer::sleep(Duration::from_millis(20)).await;
+    });
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn timeout_elapses() {
+    let result = run(timer::timeout(
+        Duration::from_millis(5),
+        timer::sleep(Duration::from_secs(10)),
+    ));
+    assert_eq!(result, Err(timer::Elapsed));
+    let result = run(timer::timeout(Duration::from_secs(10), async { 42 }));
+    assert_eq!(result, Ok(42));
+}
+
+#[test_case]
+fn cancelled_timeout_leaves_the_queue() {
+    let queued = timer::queued_timers();
+    {
+        let mut timeout = pin!(timer::timeout(
+            Duration::from_secs(60),
+            core::future::pending::<()>()
+        ));
+        let mut context = Context::from_waker(Waker::noop());
+        assert!(timeout.as_mut().poll(&mut context).is_pending());
+        assert_eq!(timer::queued_timers(), queued + 1);
+    }
+    assert!(timer::queued_timers() <= queued);
+}
+
+#[test_case]
+fn interval_ticks_periodically() {
+    let mut interval = timer::interval(Duration::from_millis(5));
+    let (first, third) = run(async move {
+        let first = interval.tick().await;
+        interval.tick().await;
+        (first, interval.tick().await)
+    });
+    assert!(third.duration_since(first) >= Duration::from_millis(10));
+}
+
+#[test_case]
+fn expired_timer_wakes_its_task() {
+    struct Flag(AtomicBool);
+
+    impl Wake for Flag {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::SeqCst);
+        }
+    }
+
+    let flag = Arc::new(Flag(AtomicBool::new(false)));
+    let waker = Waker::from(flag.clone());
+    let mut sleep = timer::sleep(Duration::from_millis(5));
+    let mut context = Context::from_waker(&waker);
+    assert!(Pin::new(&mut sleep).poll(&mut context).is_pending());
+    while !flag.0.load(Ordering::SeqCst) {
+        x86_64::instructions::hlt();
+        timer::wake_expired();
+    }
+    assert!(Pin::new(&mut sleep).poll(&mut context).is_ready());
+}
+
+/// Polls `future` to completion, busy waiting in between.
+fn run<F: Future>(future: F) -> F::Output {
+    let waker = Waker::noop();
+    let mut context = Context::from_waker(waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            return output;
+        }
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn rtc_reads_a_plausible_date() {
+    let now = rtc::read();
+    assert!(now.year >= 2024, "{}", now);
+    assert!((1..=12).contains(&now.month) && (1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn rtc_periodic_interrupt_ticks() {
+    // 1024 Hz
+    rtc::enable_periodic_interrupt(6).unwrap();
+    let ticks = rtc::periodic_ticks();
+    while rtc::periodic_ticks() < ticks + 3 {
+        x86_64::instructions::hlt();
+    }
+    rtc::disable_periodic_interrupt();
+}
+
+#[test_case]
+fn rtc_irq_is_shared() {
+    use kernel::interrupts::{IrqError, irq_count, register_irq, unregister_irq};
+
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+    fn count(_irq: u8) -> bool {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        false
+    }
+
+    register_irq(8, "count", count).unwrap();
+    assert_eq!(
+        register_irq(8, "count", count),
+        Err(IrqError::AlreadyRegistered)
+    );
+    rtc::enable_periodic_interrupt(6).unwrap();
+    let irqs = irq_count(8);
+    while CALLS.load(Ordering::Relaxed) < 3 {
+        x86_64::instructions::hlt();
+    }
+    rtc::disable_periodic_interrupt();
+    unregister_irq(8, "count").unwrap();
+    assert!(irq_count(8) >= irqs + 3);
+    assert_eq!(unregister_irq(8, "count"), Err(IrqError::NotRegistered));
+}
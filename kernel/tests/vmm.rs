@@ -0,0 +1,217 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(kernel::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader_api::{BootInfo, entry_point};
+use core::panic::PanicInfo;
+use kernel::memory::{self, cow, report, vmm};
+use x86_64::structures::paging::{PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+entry_point!(main, config = &kernel::BOOTLOADER_CONFIG);
+
+fn main(boot_info: &'static mut BootInfo) -> ! {
+    use kernel::allocator;
+    use kernel::memory::BitmapFrameAllocator;
+
+    kernel::init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let mapper = unsafe { memory::init(phys_mem_offset) };
+    let frame_allocator =
+        unsafe { BitmapFrameAllocator::init(&boot_info.memory_regions, phys_mem_offset) };
+    memory::init_global(mapper, frame_allocator);
+    allocator::init_heap().expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    kernel::test_panic_handler(info)
+}
+
+#[test_case]
+fn reserve_does_not_overlap() {
+    let a = vmm::reserve(3 * 4096).unwrap();
+    let b = vmm::reserve(4096).unwrap();
+    assert_eq!(a.size(), 3 * 4096);
+    assert!(b.start() >= a.end() || b.end() <= a.start());
+    vmm::release(a);
+    vmm::release(b);
+}
+
+#[test_case]
+fn map_write_unmap() {
+    let range = vmm::reserve(4 * 4096).unwrap();
+    let free_before = memory::frame_allocator().free_frames();
+    vmm::map(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+
+    let ptr: *mut u64 = range.start().as_mut_ptr();
+    unsafe {
+        ptr.write_volatile(0xdead_beef);
+        assert_eq!(ptr.read_volatile(), 0xdead_beef);
+    }
+    assert!(vmm::translate(range.start()).is_some());
+
+    vmm::unmap(range, true);
+    assert!(vmm::translate(range.start()).is_none());
+    // the data frames and the page tables that became empty are freed again
+    assert_eq!(memory::frame_allocator().free_frames(), free_before);
+    vmm::release(range);
+}
+
+#[test_case]
+fn protect_read_only() {
+    let range = vmm::reserve(4096).unwrap();
+    vmm::map(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+    unsafe { vmm::protect(range, PageTableFlags::PRESENT).unwrap() };
+    let ptr: *const u64 = range.start().as_ptr();
+    assert_eq!(unsafe { ptr.read_volatile() }, 0);
+    vmm::unmap(range, true);
+    vmm::release(range);
+}
+
+#[test_case]
+fn map_mmio_translates_to_device() {
+    let vga = vmm::map_mmio(PhysAddr::new(0xb8010), 0x100).unwrap();
+    assert_eq!(vga.as_u64() % 4096, 0x10);
+    assert_eq!(vmm::translate(vga), Some(PhysAddr::new(0xb8010)));
+}
+
+#[test_case]
+fn lazy_range_is_backed_on_first_touch() {
+    let range = vmm::reserve(64 * 4096).unwrap();
+    let free_before = memory::frame_allocator().free_frames();
+    vmm::map_lazy(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    assert!(vmm::translate(range.start()).is_none());
+
+    let ptr: *mut u64 = (range.start() + 5 * 4096u64).as_mut_ptr();
+    unsafe {
+        assert_eq!(ptr.read_volatile(), 0);
+        ptr.write_volatile(42);
+        assert_eq!(ptr.read_volatile(), 42);
+    }
+    assert!(vmm::translate(range.start() + 5 * 4096u64).is_some());
+    assert!(vmm::translate(range.start()).is_none());
+    // only the touched page (and its page tables) use memory
+    assert!(free_before - memory::frame_allocator().free_frames() <= 4);
+
+    vmm::unmap(range, true);
+    assert_eq!(memory::frame_allocator().free_frames(), free_before);
+    vmm::release(range);
+}
+
+#[test_case]
+fn snapshot_copies_on_write() {
+    let range = vmm::reserve(2 * 4096).unwrap();
+    let free_before = memory::frame_allocator().free_frames();
+    vmm::map(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+    let original: *mut u64 = range.start().as_mut_ptr();
+    unsafe { original.write_volatile(1) };
+
+    let copy = cow::snapshot(range).unwrap();
+    let snapshot: *mut u64 = copy.start().as_mut_ptr();
+    assert_eq!(vmm::translate(range.start()), vmm::translate(copy.start()));
+    let frame = PhysFrame::containing_address(vmm::translate(range.start()).unwrap());
+    assert_eq!(cow::mappings(frame), 2);
+
+    // the first write gives the original its own frame, the snapshot keeps the old data
+    unsafe { original.write_volatile(2) };
+    assert_ne!(vmm::translate(range.start()), vmm::translate(copy.start()));
+    assert_eq!(cow::mappings(frame), 1);
+    assert_eq!(unsafe { snapshot.read_volatile() }, 1);
+    assert_eq!(unsafe { original.read_volatile() }, 2);
+
+    // the last mapping of a frame just becomes writable again
+    unsafe { snapshot.write_volatile(3) };
+    assert_eq!(vmm::translate(copy.start()).unwrap(), frame.start_address());
+
+    vmm::unmap(copy, true);
+    vmm::unmap(range, true);
+    assert_eq!(memory::frame_allocator().free_frames(), free_before);
+    vmm::release(copy);
+    vmm::release(range);
+}
+
+#[test_case]
+fn snapshot_of_large_range() {
+    const HUGE_PAGE: u64 = 2 * 1024 * 1024;
+    let range = vmm::reserve(HUGE_PAGE).unwrap();
+    assert!(range.start().is_aligned(HUGE_PAGE));
+    vmm::map(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+    let original: *mut u64 = (range.start() + 4096u64).as_mut_ptr();
+    unsafe { original.write_volatile(1) };
+
+    // plain mappings use 4 KiB pages even where a huge page would fit
+    let copy = cow::snapshot(range).unwrap();
+    let snapshot: *mut u64 = (copy.start() + 4096u64).as_mut_ptr();
+    unsafe { original.write_volatile(2) };
+    assert_eq!(unsafe { snapshot.read_volatile() }, 1);
+
+    vmm::unmap(copy, true);
+    vmm::unmap(range, true);
+    vmm::release(copy);
+    vmm::release(range);
+}
+
+#[test_case]
+fn large_range_uses_huge_pages() {
+    const HUGE_PAGE: u64 = 2 * 1024 * 1024;
+    let range = vmm::reserve(HUGE_PAGE + 4096).unwrap();
+    assert!(range.start().is_aligned(HUGE_PAGE));
+    let free_before = memory::frame_allocator().free_frames();
+    vmm::map_huge(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+
+    // the first 2 MiB are one physically contiguous, aligned frame
+    let start = vmm::translate(range.start()).unwrap();
+    assert!(start.is_aligned(HUGE_PAGE));
+    assert_eq!(
+        vmm::translate(range.start() + 0x1234u64),
+        Some(start + 0x1234u64)
+    );
+    assert_eq!(
+        vmm::translate(range.start() + (HUGE_PAGE - 8)),
+        Some(start + (HUGE_PAGE - 8))
+    );
+    let tail: *mut u64 = (range.start() + HUGE_PAGE).as_mut_ptr();
+    unsafe { tail.write_volatile(7) };
+
+    vmm::unmap(range, true);
+    assert!(vmm::translate(range.start()).is_none());
+    assert_eq!(memory::frame_allocator().free_frames(), free_before);
+    vmm::release(range);
+}
+
+#[test_case]
+fn remap_physical_memory_keeps_translations() {
+    let offset = memory::mapper().phys_offset();
+    let addrs: [u64; 3] = [0x1000, 0x20_0000 + 0x1234, 0x100_0000 - 8];
+    let translate_all = || addrs.map(|addr| vmm::translate(offset + addr));
+    assert_eq!(translate_all(), addrs.map(|addr| Some(PhysAddr::new(addr))));
+
+    vmm::remap_physical_memory();
+    assert_eq!(translate_all(), addrs.map(|addr| Some(PhysAddr::new(addr))));
+    // everything that could be merged is merged already
+    assert_eq!(vmm::remap_physical_memory(), 0);
+}
+
+#[test_case]
+fn frame_usage_report() {
+    let before = report::frame_stats();
+    assert_eq!(before.used_frames + before.free_frames, before.total_frames);
+    let range = vmm::reserve(4096).unwrap();
+    vmm::map(range, PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
+    assert!(report::frame_stats().used_frames > before.used_frames);
+    vmm::unmap(range, true);
+    vmm::release(range);
+    assert_eq!(report::frame_stats(), before);
+
+    assert_eq!(format!("{}", report::Bytes(512)), "512 B");
+    assert_eq!(format!("{}", report::Bytes(3 * 512 * 1024)), "1.5 MiB");
+}